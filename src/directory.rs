@@ -9,14 +9,135 @@ pub struct Directory{
 }
 
 impl Directory {
+    /// 按选项过滤并排序，排序稳定，分组在最后进行，不受 reverse 影响
+    pub fn arrange(&mut self, option : &ListOption) {
+        self.item.retain(|item| {
+            !(option.hide_dot && item.name.starts_with('.')) &&
+            !(option.hide_hidden && (item.attribute.contains(Attribute::HIDDEN) ||
+                item.attribute.contains(Attribute::SYSTEM)))
+        });
+        match option.sort {
+            SortBy::Disk => {}
+            SortBy::Name => self.item.sort_by(|a, b| a.name.cmp(&b.name)),
+            SortBy::FoldedName => self.item.sort_by(|a, b| {
+                folded_cmp(&a.name[..], &b.name[..]).then_with(|| a.name.cmp(&b.name))
+            }),
+            SortBy::NaturalName => self.item.sort_by(|a, b| {
+                natural_cmp(&a.name[..], &b.name[..]).then_with(|| a.name.cmp(&b.name))
+            }),
+            SortBy::Size => self.item.sort_by(|a, b| a.size.cmp(&b.size).then_with(|| a.name.cmp(&b.name))),
+            SortBy::Mtime => self.item.sort_by(|a, b| a.mtime.cmp(&b.mtime).then_with(|| a.name.cmp(&b.name))),
+        }
+        if option.reverse {
+            self.item.reverse();
+        }
+        if option.directories_first {
+            self.item.sort_by_key(|item| !item.is_dir());
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortBy {
+    /// 保持磁盘中的顺序，文件在前，目录在后
+    Disk,
+    Name,
+    /// 不区分 ASCII 大小写
+    FoldedName,
+    /// 数字按数值比较，如 file2 在 file10 之前，同时不区分 ASCII 大小写
+    NaturalName,
+    Size,
+    Mtime,
+}
+
+/// ## 列目录选项
+pub struct ListOption {
+    pub sort : SortBy,
+    pub directories_first : bool,
+    /// 隐藏以 '.' 开头的项
+    pub hide_dot : bool,
+    /// 隐藏带有 HIDDEN 或 SYSTEM 属性的项
+    pub hide_hidden : bool,
+    pub reverse : bool,
+}
+
+impl ListOption {
+    pub fn new()->Self {
+        Self {
+            sort : SortBy::Disk,
+            directories_first : false,
+            hide_dot : false,
+            hide_hidden : false,
+            reverse : false,
+        }
+    }
+}
+
+fn folded_cmp(a : &str, b : &str)->Ordering {
+    a.bytes().map(|c| c.to_ascii_lowercase()).cmp(b.bytes().map(|c| c.to_ascii_lowercase()))
+}
+
+/// 连续的数字去掉前导零后按数值比较，数值相同时前导零少的在前
+fn natural_cmp(a : &str, b : &str)->Ordering {
+    let mut a = a.as_bytes();
+    let mut b = b.as_bytes();
+    loop {
+        match (a.first(), b.first()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let da = a.iter().take_while(|c| c.is_ascii_digit()).count();
+                let db = b.iter().take_while(|c| c.is_ascii_digit()).count();
+                let na = &a[a[..da].iter().take_while(|c| **c == b'0').count()..da];
+                let nb = &b[b[..db].iter().take_while(|c| **c == b'0').count()..db];
+                let ord = na.len().cmp(&nb.len()).then_with(|| na.cmp(nb)).then_with(|| da.cmp(&db));
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                a = &a[da..];
+                b = &b[db..];
+            }
+            (Some(x), Some(y)) => {
+                let ord = x.to_ascii_lowercase().cmp(&y.to_ascii_lowercase());
+                if ord != Ordering::Equal {
+                    return ord;
+                }
+                a = &a[1..];
+                b = &b[1..];
+            }
+        }
+    }
 }
 
 pub struct DirectoryItem {
     pub name : String,
     pub itype : DirItemType,
+    pub size : usize,
+    pub block_idx : usize,
+    pub permission : Permission,
+    pub ctime : usize,
+    pub mtime : usize,
+    pub atime : usize,
+    pub attribute : Attribute,
 }
 
 impl DirectoryItem {
+    /// permission 为挂载选项处理后的权限
+    pub fn new(name : String, leaf : &Leaf, permission : Permission)->Self {
+        Self {
+            name,
+            itype : if leaf.is_directory() { DirItemType::Directory } else { DirItemType::File },
+            size : leaf.size,
+            block_idx : leaf.block_idx,
+            permission,
+            ctime : leaf.ctime,
+            mtime : leaf.mtime,
+            atime : leaf.atime,
+            attribute : leaf.attribute,
+        }
+    }
+
     pub fn is_file(&self)->bool {
         self.itype == DirItemType::File
     }
@@ -31,8 +152,38 @@ impl Clone for DirectoryItem {
         Self {
             name:self.name.clone(),
             itype:self.itype,
+            size:self.size,
+            block_idx:self.block_idx,
+            permission:self.permission,
+            ctime:self.ctime,
+            mtime:self.mtime,
+            atime:self.atime,
+            attribute:self.attribute,
+        }
+    }
+}
+
+/// ## 目录变化
+/// 刷新目录时与缓存比较的结果，类型改变的项同时出现在 removed 与 added 中
+pub struct DirectoryDiff {
+    pub added : Vec<Leaf>,
+    pub removed : Vec<Leaf>,
+    /// 内容改变的项，改变前与改变后
+    pub changed : Vec<(Leaf, Leaf)>,
+}
+
+impl DirectoryDiff {
+    pub fn new()->Self {
+        Self {
+            added : Vec::new(),
+            removed : Vec::new(),
+            changed : Vec::new(),
         }
     }
+
+    pub fn is_empty(&self)->bool {
+        self.added.len() == 0 && self.removed.len() == 0 && self.changed.len() == 0
+    }
 }
 
 #[derive(PartialEq, Clone, Copy)]
@@ -42,4 +193,34 @@ pub enum DirItemType {
 }
 
 
+use core::cmp::Ordering;
 use alloc::prelude::v1::*;
+use crate::{Attribute, Leaf, Permission};
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn numbers_compare_by_value() {
+        assert_eq!(natural_cmp("file2", "file10"), Ordering::Less);
+        assert_eq!(natural_cmp("file10", "file2"), Ordering::Greater);
+        assert_eq!(natural_cmp("x10y", "x10y"), Ordering::Equal);
+        assert_eq!(natural_cmp("a10b2", "a10b10"), Ordering::Less);
+    }
+
+    #[test]
+    fn fewer_leading_zeros_first() {
+        assert_eq!(natural_cmp("a1", "a01"), Ordering::Less);
+        assert_eq!(natural_cmp("007", "7"), Ordering::Greater);
+        assert_eq!(natural_cmp("a01", "a2"), Ordering::Less);
+    }
+
+    #[test]
+    fn letters_ignore_case() {
+        assert_eq!(natural_cmp("B", "a"), Ordering::Greater);
+        assert_eq!(natural_cmp("abc", "ABD"), Ordering::Less);
+        assert_eq!(natural_cmp("abc", "abcd"), Ordering::Less);
+        assert_eq!(natural_cmp("1", "a"), Ordering::Less);
+    }
+}
@@ -1,3 +1,5 @@
+use alloc::prelude::v1::*;
+
 pub struct DiskInfo {
     pub stype : SystemType,
     pub total_size: usize,
@@ -5,10 +7,17 @@ pub struct DiskInfo {
     pub root_directory_block_idx : usize,
     /// 第一个块开始的地址
     pub block_start_addr: usize,
+    /// 卷标，没有时为空
+    pub label : String,
+    /// 没有 UUID 的格式给出卷序列号，如 FAT32 的 1234-ABCD
+    pub uuid : String,
+    /// 格式版本，如 FAT32 的 BPB_FSVer
+    pub version : usize,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum SystemType {
     FAT32,
     Tianmu,
     Unknown,
-}
\ No newline at end of file
+}
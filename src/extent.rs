@@ -0,0 +1,79 @@
+//! # 区间
+//! 文件的块以连续区间描述，一次传输可以覆盖整个区间
+
+use alloc::prelude::v1::*;
+
+/// 从 start 开始连续的 len 个块
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Extent {
+    pub start : usize,
+    pub len : usize,
+}
+
+impl Extent {
+    /// 将块链中相邻的块合并为区间
+    pub fn merge(chain : &[usize])->Vec<Extent> {
+        let mut rt : Vec<Extent> = Vec::new();
+        for block in chain.iter() {
+            if let Some(last) = rt.last_mut() {
+                if last.start + last.len == *block {
+                    last.len += 1;
+                    continue;
+                }
+            }
+            rt.push(Extent { start : *block, len : 1 });
+        }
+        rt
+    }
+}
+
+/// ## 块映射
+/// 文件内块号到磁盘块号的映射，offset 记录每个区间在文件中的起始块号
+#[derive(Debug)]
+pub struct ExtentMap {
+    extents : Vec<Extent>,
+    offset : Vec<usize>,
+}
+
+impl ExtentMap {
+    pub fn new(extents : Vec<Extent>)->Self {
+        let extents : Vec<Extent> = extents.into_iter().filter(|e| e.len > 0).collect();
+        let mut offset = Vec::new();
+        let mut n = 0;
+        for e in extents.iter() {
+            offset.push(n);
+            n += e.len;
+        }
+        Self {
+            extents,
+            offset,
+        }
+    }
+
+    pub fn extents(&self)->&[Extent] {
+        &self.extents[..]
+    }
+
+    /// 总块数
+    pub fn blocks(&self)->usize {
+        match self.extents.last() {
+            Some(e) => self.offset[self.offset.len() - 1] + e.len,
+            None => 0,
+        }
+    }
+
+    /// 文件内第 idx 块对应的磁盘块号，以及从该块开始还有多少连续块
+    pub fn map(&self, idx : usize)->Option<(usize, usize)> {
+        let i = match self.offset.binary_search(&idx) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        let e = self.extents[i];
+        let skip = idx - self.offset[i];
+        if skip >= e.len {
+            return None;
+        }
+        Some((e.start + skip, e.len - skip))
+    }
+}
@@ -0,0 +1,168 @@
+//! # 文件描述符
+//! 文件分为三层：任务的描述符表 FdTable -> 打开文件描述 OpenFile -> 文件 File
+//! OpenFile 记录打开标志与读写位置，dup 与 fork 出的描述符共享同一个 OpenFile
+//! 最后一个引用关闭时，将 OpenFile 交还给文件系统关闭
+
+use core::{cmp::{max, min}, sync::atomic::{AtomicUsize, Ordering}};
+use alloc::{collections::BTreeMap, sync::Arc};
+use crate::OpenFlag;
+
+/// 0、1、2 为标准输入输出，不分配
+pub const FD_START : usize = 3;
+
+/// 检测到顺序读后的初始预读块数，之后每次顺序读翻倍
+const READ_AHEAD_START : usize = 2;
+
+/// ## 打开文件描述
+/// 每次 open 产生一个，由文件系统创建
+#[derive(Debug)]
+pub struct OpenFile {
+    pub device_id : usize,
+    pub file_id : usize,
+    /// 打开者，关闭时从文件的所有者中移除
+    pub task_id : usize,
+    pub flag : OpenFlag,
+    offset : AtomicUsize,
+    /// 上次读取结束的位置，下次从这里开始即为顺序读
+    next : AtomicUsize,
+    /// 预读窗口的块数
+    window : AtomicUsize,
+    /// 已预读到的文件内块号
+    ahead : AtomicUsize,
+}
+
+impl OpenFile {
+    pub fn new(device_id : usize, file_id : usize, task_id : usize, flag : OpenFlag)->Self {
+        Self {
+            device_id,
+            file_id,
+            task_id,
+            flag,
+            offset : AtomicUsize::new(0),
+            next : AtomicUsize::new(0),
+            window : AtomicUsize::new(0),
+            ahead : AtomicUsize::new(0),
+        }
+    }
+
+    pub fn readable(&self)->bool {
+        self.flag.readable()
+    }
+
+    pub fn writable(&self)->bool {
+        self.flag.writable()
+    }
+
+    pub fn offset(&self)->usize {
+        self.offset.load(Ordering::SeqCst)
+    }
+
+    /// 移动位置同时重置预读，之后的第一次读取不算顺序读
+    pub fn seek(&self, offset : usize) {
+        self.offset.store(offset, Ordering::SeqCst);
+        self.next.store(usize::MAX, Ordering::SeqCst);
+        self.window.store(0, Ordering::SeqCst);
+        self.ahead.store(0, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, len : usize) {
+        self.offset.fetch_add(len, Ordering::SeqCst);
+    }
+
+    /// 记录一次读取 [st, ed)，返回预读窗口的块数
+    /// 顺序读时窗口翻倍直到 limit，否则清零
+    pub fn sequential(&self, st : usize, ed : usize, limit : usize)->usize {
+        let window = if st == self.next.load(Ordering::SeqCst) {
+            min(max(self.window.load(Ordering::SeqCst) * 2, READ_AHEAD_START), limit)
+        }
+        else {
+            self.ahead.store(0, Ordering::SeqCst);
+            0
+        };
+        self.window.store(window, Ordering::SeqCst);
+        self.next.store(ed, Ordering::SeqCst);
+        window
+    }
+
+    pub fn ahead(&self)->usize {
+        self.ahead.load(Ordering::SeqCst)
+    }
+
+    pub fn set_ahead(&self, block : usize) {
+        self.ahead.store(block, Ordering::SeqCst);
+    }
+}
+
+/// ## 描述符表
+/// 每个任务一张，fork 时复制
+pub struct FdTable {
+    table : BTreeMap<usize, Arc<OpenFile>>,
+}
+
+impl FdTable {
+    pub fn new()->Self {
+        Self {
+            table : BTreeMap::new(),
+        }
+    }
+
+    /// 分配最小的空闲描述符
+    pub fn insert(&mut self, file : Arc<OpenFile>)->usize {
+        let mut fd = FD_START;
+        while self.table.contains_key(&fd) {
+            fd += 1;
+        }
+        self.table.insert(fd, file);
+        fd
+    }
+
+    pub fn get(&self, fd : usize)->Option<Arc<OpenFile>> {
+        self.table.get(&fd).cloned()
+    }
+
+    pub fn dup(&mut self, fd : usize)->Result<usize, FdError> {
+        let file = self.get(fd).ok_or(FdError::BadFd(fd))?;
+        Ok(self.insert(file))
+    }
+
+    /// 让 new_fd 指向 fd 的打开文件描述，原来占用 new_fd 的描述需要关闭时返回
+    pub fn dup2(&mut self, fd : usize, new_fd : usize)->Result<Option<OpenFile>, FdError> {
+        let file = self.get(fd).ok_or(FdError::BadFd(fd))?;
+        if fd == new_fd {
+            return Ok(None);
+        }
+        Ok(self.table.insert(new_fd, file).and_then(|old| Arc::try_unwrap(old).ok()))
+    }
+
+    /// 移除描述符，若是最后一个引用则返回打开文件描述，调用者需交给文件系统关闭
+    pub fn close(&mut self, fd : usize)->Result<Option<OpenFile>, FdError> {
+        let file = self.table.remove(&fd).ok_or(FdError::BadFd(fd))?;
+        Ok(Arc::try_unwrap(file).ok())
+    }
+
+    /// 任务退出时关闭所有描述符
+    pub fn clear(&mut self)->Vec<OpenFile> {
+        let mut rt = Vec::new();
+        let table = core::mem::replace(&mut self.table, BTreeMap::new());
+        for (_, file) in table {
+            if let Ok(file) = Arc::try_unwrap(file) {
+                rt.push(file);
+            }
+        }
+        rt
+    }
+
+    /// 复制描述符表，新表与原表共享打开文件描述
+    pub fn fork(&self)->Self {
+        Self {
+            table : self.table.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FdError {
+    BadFd(usize),
+}
+
+use alloc::prelude::v1::*;
@@ -4,11 +4,19 @@
 pub struct File{
     pub id : usize,
     pub device_id : usize,
+    /// 所在目录的起始块号，打开时取得，产生监视事件时使用
+    pub dir_idx : usize,
     pub start_idx : usize,
     pub name : String,
     pub path : String,
     pub state : FileState,
     pub size : usize,
+    pub permission : Permission,
+    pub ltype : LeafType,
+    /// 已解析的块映射，文件大小改变时失效
+    pub extents : Option<Arc<ExtentMap>>,
+    /// 刷新目录时发现文件已不存在，之后的读写返回 IoError::NotFound
+    pub deleted : bool,
 }
 
 impl Clone for File {
@@ -16,30 +24,24 @@ impl Clone for File {
         Self {
             id : self.id,
             device_id:self.device_id,
+            dir_idx:self.dir_idx,
             start_idx : self.start_idx,
             name:self.name.clone(),
             path : self.path.clone(),
             state:self.state.clone(),
             size:self.size,
+            permission:self.permission,
+            ltype:self.ltype,
+            extents:self.extents.clone(),
+            deleted:self.deleted,
         }
     }
 }
 
 impl File {
-    pub fn open(&mut self, flag : FileFlag)->Result<(), FileError> {
-        if self.state.is_close() {
-            self.state.set(flag);
-            Ok(())
-        }
-        else {
-            match flag {
-                FileFlag::Read =>
-                    if !self.state.writable() {Ok(())} else {Err(FileError::ReadFromWrite)},
-                FileFlag::Write =>
-                    if self.state.is_close() {Ok(())} else {Err(FileError::WriteToReadOnly)},
-                _ => Err(FileError::FlagErr(flag)),
-            }
-        }
+    /// 按共享方式登记一次打开，冲突时不改变状态
+    pub fn open(&mut self, flag : FileFlag, share : ShareMode)->Result<(), FileError> {
+        self.state.open(flag, share)
     }
 
     pub fn readable(&self)->bool {
@@ -54,8 +56,13 @@ impl File {
         self.state.read_only()
     }
 
-    pub fn close(&mut self) {
-        self.state.flag = FileFlag::Close;
+    /// 撤销一次以 flag 方式的打开，所有打开都撤销后文件关闭
+    pub fn close(&mut self, flag : FileFlag) {
+        self.state.close(flag)
+    }
+
+    pub fn is_close(&self)->bool {
+        self.state.is_close()
     }
 
     pub fn is_own(&self, task_id : usize)->bool {
@@ -65,6 +72,10 @@ impl File {
     pub fn own(&mut self, task_id : usize) {
         self.state.own(task_id)
     }
+
+    pub fn disown(&mut self, task_id : usize) {
+        self.state.disown(task_id)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -72,6 +83,20 @@ pub enum FileError {
     WriteToReadOnly,
     ReadFromWrite,
     FlagErr(FileFlag),
+    /// 共享方式不允许再次打开
+    Busy,
+}
+
+/// ## 共享方式
+/// 同一文件的所有打开必须使用相同的共享方式
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ShareMode {
+    /// 多个读者或一个写者
+    ReadShared,
+    /// 读写均可共享
+    Shared,
+    /// 只允许一次打开
+    Exclusive,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -97,38 +122,153 @@ impl FileFlag{
     }
 }
 
+/// ## 打开方式
+/// 低两位为读写方式，与 FileFlag 一致，其余位控制打开行为
+/// libc 的 fopen 模式可以直接映射：
+/// r -> READ, w -> WRITE | CREATE | TRUNCATE, a -> WRITE | CREATE | APPEND
+/// 带 + 时换成 READ_WRITE，带 x 时加上 EXCLUSIVE
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpenFlag(usize);
+
+impl OpenFlag {
+    pub const READ : Self = Self(1);
+    pub const WRITE : Self = Self(2);
+    pub const READ_WRITE : Self = Self(3);
+    /// 不存在时创建
+    pub const CREATE : Self = Self(1 << 2);
+    /// 打开时将长度置零，需要写方式
+    pub const TRUNCATE : Self = Self(1 << 3);
+    /// 每次写入前移动到文件末尾
+    pub const APPEND : Self = Self(1 << 4);
+    /// 与 CREATE 一起使用，文件已存在时失败
+    pub const EXCLUSIVE : Self = Self(1 << 5);
+    /// 只允许打开目录
+    pub const DIRECTORY : Self = Self(1 << 6);
+
+    const ACCESS_MASK : usize = 3;
+    const ALL : usize = (1 << 7) - 1;
+
+    pub fn val(self)->usize {
+        self.0
+    }
+
+    /// 必须包含读写方式，目录只能以只读方式打开
+    pub fn from(n : usize)->Option<Self> {
+        let flag = Self(n);
+        if n & !Self::ALL != 0 || n & Self::ACCESS_MASK == 0 ||
+            (flag.contains(Self::DIRECTORY) && flag.writable()) {
+            None
+        }
+        else {
+            Some(flag)
+        }
+    }
+
+    pub fn contains(self, other : Self)->bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// 没有读写方式时为 Close，这样的标志不能用于打开
+    pub fn access(self)->FileFlag {
+        FileFlag::from(self.0 & Self::ACCESS_MASK).unwrap_or(FileFlag::Close)
+    }
+
+    pub fn readable(self)->bool {
+        self.contains(Self::READ)
+    }
+
+    pub fn writable(self)->bool {
+        self.contains(Self::WRITE)
+    }
+}
+
+impl BitOr for OpenFlag {
+    type Output = Self;
+
+    fn bitor(self, rhs : Self)->Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// ## 文件状态
+/// 按读写方式分别计数，ReadWrite 同时计入读者与写者
+/// owner 中每次打开对应一项，关闭时移除
 #[derive(Debug)]
 pub struct FileState {
-    pub flag : FileFlag,
+    pub readers : usize,
+    pub writers : usize,
+    pub share : ShareMode,
     pub owner : Vec<usize>,
 }
 
 impl FileState {
     pub fn new()->Self {
         Self {
-            flag: FileFlag::Close,
+            readers : 0,
+            writers : 0,
+            share : ShareMode::ReadShared,
             owner:Vec::new(),
         }
     }
 
     pub fn is_close(&self)->bool {
-        self.flag == FileFlag::Close
+        self.readers == 0 && self.writers == 0
+    }
+
+    pub fn open(&mut self, flag : FileFlag, share : ShareMode)->Result<(), FileError> {
+        if flag == FileFlag::Close {
+            return Err(FileError::FlagErr(flag));
+        }
+        let read = flag == FileFlag::Read || flag == FileFlag::ReadWrite;
+        let write = flag == FileFlag::Write || flag == FileFlag::ReadWrite;
+        if self.is_close() {
+            self.share = share;
+        }
+        else if share != self.share {
+            return Err(FileError::Busy);
+        }
+        else {
+            match share {
+                ShareMode::Exclusive => return Err(FileError::Busy),
+                ShareMode::ReadShared => {
+                    if self.writers > 0 {
+                        return Err(if write { FileError::Busy } else { FileError::ReadFromWrite });
+                    }
+                    if write {
+                        return Err(FileError::WriteToReadOnly);
+                    }
+                }
+                ShareMode::Shared => {}
+            }
+        }
+        if read {
+            self.readers += 1;
+        }
+        if write {
+            self.writers += 1;
+        }
+        Ok(())
     }
 
-    pub fn set(&mut self, flag : FileFlag) {
-        self.flag = flag;
+    pub fn close(&mut self, flag : FileFlag) {
+        if (flag == FileFlag::Read || flag == FileFlag::ReadWrite) && self.readers > 0 {
+            self.readers -= 1;
+        }
+        if (flag == FileFlag::Write || flag == FileFlag::ReadWrite) && self.writers > 0 {
+            self.writers -= 1;
+        }
     }
 
     pub fn readable(&self)->bool {
-        self.flag == FileFlag::Read || self.flag == FileFlag::ReadWrite
+        self.readers > 0
     }
 
     pub fn read_only(&self)->bool {
-        self.flag == FileFlag::Read
+        self.readers > 0 && self.writers == 0
     }
 
     pub fn writable(&self)->bool {
-        self.flag == FileFlag::Write || self.flag == FileFlag::ReadWrite
+        self.writers > 0
     }
 
     pub fn is_own(&self, task_id : usize)->bool {
@@ -138,16 +278,28 @@ impl FileState {
     pub fn own(&mut self, task_id : usize) {
         self.owner.push(task_id)
     }
+
+    /// 只移除一项，同一任务多次打开时仍然保有其余的
+    pub fn disown(&mut self, task_id : usize) {
+        if let Some(idx) = self.owner.iter().position(|id| *id == task_id) {
+            self.owner.remove(idx);
+        }
+    }
 }
 
 impl Clone for FileState {
     fn clone(&self) -> Self {
         Self {
-            flag:self.flag,
+            readers:self.readers,
+            writers:self.writers,
+            share:self.share,
             owner:self.owner.clone(),
         }
     }
 }
 
-
 use alloc::prelude::v1::*;
+use alloc::sync::Arc;
+use crate::{ExtentMap, Permission};
+use crate::LeafType;
+use core::ops::BitOr;
@@ -1,28 +1,77 @@
-use tisu_sync::AtomCounter;
-use alloc::prelude::v1::*;
+use tisu_sync::{AtomCounter, ContentMutex};
+use alloc::{collections::BTreeMap, prelude::v1::*};
 
+/// 句柄高半部分为代数，低半部分为编号
+/// 编号回收后代数加一，持有旧句柄的任务无法访问新打开的文件
+const GENERATION_SHIFT : usize = core::mem::size_of::<usize>() * 4;
+const INDEX_MASK : usize = (1 << GENERATION_SHIFT) - 1;
+
+/// ## 句柄管理
+/// 多个文件系统共享，内部加锁
 pub struct IdManager {
+    inner : ContentMutex<IdInner>,
+}
+
+struct IdInner {
     id : AtomCounter,
     used : Vec<usize>,
+    generation : BTreeMap<usize, usize>,
 }
 
 impl IdManager {
     pub fn new()->Self {
         Self {
-            id:AtomCounter::new(),
-            used : Vec::new(),
+            inner : ContentMutex::new(IdInner {
+                id:AtomCounter::new(),
+                used : Vec::new(),
+                generation : BTreeMap::new(),
+            }),
         }
     }
 
-    /// 预留 0、1 给标准输入输出
-    pub fn get(&mut self)->usize {
-        if let Some(id) = self.used.pop() { id }
+    /// 预留 0、1、2 给标准输入、输出、错误
+    pub fn get(&self)->usize {
+        let mut inner = self.inner.lock();
+        let idx = if let Some(idx) = inner.used.pop() { idx }
         else {
-            let mut rt = self.id.add();
-            while rt <= 1 {
-                rt = self.id.add();
+            let mut rt = inner.id.add();
+            while rt <= 2 {
+                rt = inner.id.add();
             }
             rt
+        };
+        idx | (inner.current(idx) << GENERATION_SHIFT)
+    }
+
+    /// 回收句柄，重复释放或释放过期句柄不产生效果
+    pub fn release(&self, handle : usize) {
+        let mut inner = self.inner.lock();
+        if !inner.valid(handle) {
+            return;
         }
+        let idx = Self::index(handle);
+        let generation = (inner.current(idx) + 1) & INDEX_MASK;
+        inner.generation.insert(idx, generation);
+        inner.used.push(idx);
+    }
+
+    /// 句柄未被回收且代数与当前一致
+    pub fn valid(&self, handle : usize)->bool {
+        self.inner.lock().valid(handle)
     }
-}
\ No newline at end of file
+
+    pub fn index(handle : usize)->usize {
+        handle & INDEX_MASK
+    }
+}
+
+impl IdInner {
+    fn valid(&self, handle : usize)->bool {
+        let idx = IdManager::index(handle);
+        !self.used.contains(&idx) && self.current(idx) == handle >> GENERATION_SHIFT
+    }
+
+    fn current(&self, idx : usize)->usize {
+        self.generation.get(&idx).cloned().unwrap_or(0)
+    }
+}
@@ -0,0 +1,202 @@
+//! # 通配符匹配
+//! 支持 `*`、`?`、`[a-c]`、`[!abc]`，以及匹配任意多级目录的 `**`
+//! 模式按 '/' 分段，每段匹配路径中的一级名字，`*` 与 `?` 不跨越 '/'
+//! 不区分大小写时只折叠 ASCII 字母
+
+use alloc::prelude::v1::*;
+
+pub struct Glob {
+    segments : Vec<Vec<char>>,
+    case_insensitive : bool,
+}
+
+impl Glob {
+    pub fn new(pattern : &str, case_insensitive : bool)->Self {
+        Self {
+            segments : pattern.trim_matches('/').split('/').map(|s| s.chars().collect()).collect(),
+            case_insensitive,
+        }
+    }
+
+    /// 模式中含有 '/' 时匹配多级路径
+    pub fn is_recursive(&self)->bool {
+        self.segments.len() > 1
+    }
+
+    /// 能够匹配的最大层数，含有 `**` 时不限
+    pub fn max_depth(&self)->usize {
+        if self.segments.iter().any(|s| Self::is_globstar(s)) {
+            usize::MAX
+        }
+        else {
+            self.segments.len()
+        }
+    }
+
+    /// path 为相对路径，不以 '/' 开头或结尾
+    pub fn matches(&self, path : &str)->bool {
+        let names : Vec<Vec<char>> = path.split('/').map(|s| s.chars().collect()).collect();
+        self.match_path(&self.segments[..], &names[..])
+    }
+
+    fn is_globstar(segment : &[char])->bool {
+        segment.len() == 2 && segment[0] == '*' && segment[1] == '*'
+    }
+
+    fn match_path(&self, pattern : &[Vec<char>], names : &[Vec<char>])->bool {
+        match pattern.split_first() {
+            None => names.len() == 0,
+            Some((p, rest)) if Self::is_globstar(p) =>
+                (0..=names.len()).any(|i| self.match_path(rest, &names[i..])),
+            Some((p, rest)) => names.len() > 0 && self.match_name(p, &names[0][..]) &&
+                self.match_path(rest, &names[1..]),
+        }
+    }
+
+    /// 遇到不匹配时退回到最近的 `*`，让它多吞一个字符
+    fn match_name(&self, p : &[char], n : &[char])->bool {
+        let mut pi = 0;
+        let mut ni = 0;
+        let mut star = None;
+        while ni < n.len() {
+            let step = if pi < p.len() {
+                match p[pi] {
+                    '*' => {
+                        star = Some((pi, ni));
+                        pi += 1;
+                        continue;
+                    }
+                    '?' => Some(1),
+                    '[' => match self.match_class(&p[pi..], n[ni]) {
+                        Some((true, len)) => Some(len),
+                        Some((false, _)) => None,
+                        None => if self.eq('[', n[ni]) { Some(1) } else { None },
+                    },
+                    c => if self.eq(c, n[ni]) { Some(1) } else { None },
+                }
+            }
+            else {
+                None
+            };
+            match (step, star) {
+                (Some(len), _) => {
+                    pi += len;
+                    ni += 1;
+                }
+                (None, Some((sp, sn))) => {
+                    pi = sp + 1;
+                    ni = sn + 1;
+                    star = Some((sp, sn + 1));
+                }
+                (None, None) => return false,
+            }
+        }
+        p[pi..].iter().all(|c| *c == '*')
+    }
+
+    /// p 以 '[' 开头，返回是否匹配与整个字符类的长度，没有结尾的 ']' 时返回 None
+    /// 紧跟在开头之后的 ']' 作为普通字符
+    fn match_class(&self, p : &[char], c : char)->Option<(bool, usize)> {
+        let mut i = 1;
+        let negate = i < p.len() && (p[i] == '!' || p[i] == '^');
+        if negate {
+            i += 1;
+        }
+        let start = i;
+        let mut matched = false;
+        while i < p.len() && (p[i] != ']' || i == start) {
+            if i + 2 < p.len() && p[i + 1] == '-' && p[i + 2] != ']' {
+                matched |= self.in_range(p[i], p[i + 2], c);
+                i += 3;
+            }
+            else {
+                matched |= self.eq(p[i], c);
+                i += 1;
+            }
+        }
+        if i >= p.len() {
+            return None;
+        }
+        Some((matched != negate, i + 1))
+    }
+
+    fn eq(&self, a : char, b : char)->bool {
+        if self.case_insensitive { a.eq_ignore_ascii_case(&b) } else { a == b }
+    }
+
+    fn in_range(&self, lo : char, hi : char, c : char)->bool {
+        if self.case_insensitive {
+            [c.to_ascii_lowercase(), c.to_ascii_uppercase()].iter().any(|c| lo <= *c && *c <= hi)
+        }
+        else {
+            lo <= c && c <= hi
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn star_backtracks() {
+        let glob = Glob::new("a*b*c", false);
+        assert!(glob.matches("axxbyybc"));
+        assert!(glob.matches("abbc"));
+        assert!(!glob.matches("axxbyy"));
+        assert!(Glob::new("a*bc", false).matches("abcbc"));
+        assert!(Glob::new("a*", false).matches("a"));
+    }
+
+    #[test]
+    fn star_stays_in_one_level() {
+        let glob = Glob::new("*.rs", false);
+        assert!(glob.matches("main.rs"));
+        assert!(!glob.matches("src/main.rs"));
+        assert!(!glob.is_recursive());
+        assert_eq!(glob.max_depth(), 1);
+    }
+
+    #[test]
+    fn class_with_leading_bracket() {
+        let glob = Glob::new("[]a]", false);
+        assert!(glob.matches("]"));
+        assert!(glob.matches("a"));
+        assert!(!glob.matches("b"));
+        let glob = Glob::new("[!]]", false);
+        assert!(glob.matches("x"));
+        assert!(!glob.matches("]"));
+    }
+
+    #[test]
+    fn class_range() {
+        let glob = Glob::new("[a-c]?", false);
+        assert!(glob.matches("bx"));
+        assert!(!glob.matches("dx"));
+        assert!(Glob::new("[^a-c]", false).matches("d"));
+    }
+
+    #[test]
+    fn unterminated_class_is_literal() {
+        let glob = Glob::new("[ab", false);
+        assert!(glob.matches("[ab"));
+        assert!(!glob.matches("a"));
+    }
+
+    #[test]
+    fn globstar_matches_zero_levels() {
+        let glob = Glob::new("a/**/b", false);
+        assert!(glob.matches("a/b"));
+        assert!(glob.matches("a/x/y/b"));
+        assert!(!glob.matches("a/x/c"));
+        assert!(Glob::new("**/*.rs", false).matches("main.rs"));
+        assert_eq!(glob.max_depth(), usize::MAX);
+    }
+
+    #[test]
+    fn case_folding() {
+        assert!(Glob::new("*.TXT", true).matches("a.txt"));
+        assert!(!Glob::new("*.TXT", false).matches("a.txt"));
+        assert!(Glob::new("[A-C]x", true).matches("bx"));
+    }
+}
@@ -1,13 +1,38 @@
+use core::ops::BitOr;
+
 use alloc::prelude::v1::*;
 
+use crate::Permission;
+
 pub struct Leaf {
     pub name : String,
     pub ltype : LeafType,
     pub block_idx : usize,
     pub size : usize,
+    /// 格式不支持权限位时为 None，由挂载选项决定
+    pub permission : Option<Permission>,
+    /// 时间均为 Unix 时间戳，单位为秒，格式不记录时为 0
+    pub ctime : usize,
+    pub mtime : usize,
+    pub atime : usize,
+    pub attribute : Attribute,
 }
 
 impl Leaf {
+    pub fn new(name : String, ltype : LeafType, block_idx : usize, size : usize)->Self {
+        Self {
+            name,
+            ltype,
+            block_idx,
+            size,
+            permission : None,
+            ctime : 0,
+            mtime : 0,
+            atime : 0,
+            attribute : Attribute::NONE,
+        }
+    }
+
     pub fn is_file(&self)->bool {
         self.ltype == LeafType::File
     }
@@ -15,6 +40,14 @@ impl Leaf {
     pub fn is_directory(&self)->bool {
         self.ltype == LeafType::Directory
     }
+
+    /// 同名项的内容与属性是否都相同，访问时间不比较，读取不算作变化
+    pub fn same(&self, other : &Leaf)->bool {
+        self.ltype == other.ltype && self.block_idx == other.block_idx &&
+            self.size == other.size && self.permission == other.permission &&
+            self.ctime == other.ctime && self.mtime == other.mtime &&
+            self.attribute == other.attribute
+    }
 }
 
 impl Clone for Leaf {
@@ -24,13 +57,50 @@ impl Clone for Leaf {
             ltype : self.ltype,
             block_idx : self.block_idx,
             size : self.size,
+            permission : self.permission,
+            ctime : self.ctime,
+            mtime : self.mtime,
+            atime : self.atime,
+            attribute : self.attribute,
         }
     }
 }
 
+/// ## 属性
+/// 取值与 FAT 目录项的属性字节一致，其余格式按含义转换
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attribute(usize);
+
+impl Attribute {
+    pub const NONE : Self = Self(0);
+    pub const READ_ONLY : Self = Self(1);
+    pub const HIDDEN : Self = Self(1 << 1);
+    pub const SYSTEM : Self = Self(1 << 2);
+    pub const ARCHIVE : Self = Self(1 << 5);
+
+    pub fn from(n : usize)->Self {
+        Self(n)
+    }
+
+    pub fn val(self)->usize {
+        self.0
+    }
+
+    pub fn contains(self, other : Self)->bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Attribute {
+    type Output = Self;
+
+    fn bitor(self, rhs : Self)->Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum LeafType {
     File,
     Directory,
 }
-
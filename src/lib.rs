@@ -7,7 +7,7 @@
 //!
 //! 2021年4月23日 zg
 
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![feature(
     alloc_prelude,
 )]
@@ -20,12 +20,32 @@ mod node;
 mod leaf;
 mod file_id;
 mod disk_info;
+mod permission;
+mod mount;
+mod fd_table;
+mod lock;
+mod extent;
+mod statfs;
+mod node_cache;
+mod watch;
+mod walk;
+mod glob;
 
 pub use directory::*;
-pub use file::{File, FileFlag};
-pub use system::FileSystem;
+pub use file::{File, FileError, FileFlag, OpenFlag, ShareMode};
+pub use system::{FileSystem, FileTable, FileRef, IoError, IoResult};
 pub use require::*;
 pub use file_id::IdManager;
 pub use leaf::*;
 pub use node::*;
-pub use disk_info::*;
\ No newline at end of file
+pub use disk_info::*;
+pub use permission::*;
+pub use mount::*;
+pub use fd_table::*;
+pub use lock::*;
+pub use extent::*;
+pub use statfs::*;
+pub use node_cache::*;
+pub use watch::*;
+pub use walk::*;
+pub use glob::*;
\ No newline at end of file
@@ -0,0 +1,318 @@
+//! # 文件锁
+//! 提供 flock 式整文件锁与 fcntl 式区间锁，均为建议锁，两者互不影响
+//! 锁以任务为持有者，同一任务的锁之间不冲突，任务关闭文件或退出时释放
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::{collections::BTreeMap, prelude::v1::*};
+use tisu_sync::ContentMutex;
+use crate::Wait;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockType {
+    Shared,
+    Exclusive,
+}
+
+/// 区间为 [start, end)，锁到文件末尾时 end 为 usize::MAX
+#[derive(Debug, Clone, Copy)]
+struct RangeLock {
+    owner : usize,
+    ltype : LockType,
+    start : usize,
+    end : usize,
+}
+
+impl RangeLock {
+    fn overlap(&self, start : usize, end : usize)->bool {
+        self.start < end && start < self.end
+    }
+}
+
+/// 单个文件上的所有锁
+struct FileLock {
+    whole : Vec<(usize, LockType)>,
+    range : Vec<RangeLock>,
+}
+
+impl FileLock {
+    fn new()->Self {
+        Self {
+            whole : Vec::new(),
+            range : Vec::new(),
+        }
+    }
+
+    fn is_empty(&self)->bool {
+        self.whole.len() == 0 && self.range.len() == 0
+    }
+
+    /// 已持有时视为升级或降级
+    fn try_flock(&mut self, owner : usize, ltype : LockType)->bool {
+        let conflict = self.whole.iter().any(|(o, t)| {
+            *o != owner && (*t == LockType::Exclusive || ltype == LockType::Exclusive)
+        });
+        if conflict {
+            return false;
+        }
+        self.funlock(owner);
+        self.whole.push((owner, ltype));
+        true
+    }
+
+    fn funlock(&mut self, owner : usize) {
+        self.whole.retain(|(o, _)| *o != owner);
+    }
+
+    /// 与 fcntl 一致，新锁覆盖持有者自己在该区间上的旧锁
+    fn try_lock_range(&mut self, owner : usize, ltype : LockType, start : usize, end : usize)->bool {
+        let conflict = self.range.iter().any(|l| {
+            l.owner != owner && l.overlap(start, end) &&
+                (l.ltype == LockType::Exclusive || ltype == LockType::Exclusive)
+        });
+        if conflict {
+            return false;
+        }
+        self.unlock_range(owner, start, end);
+        self.range.push(RangeLock { owner, ltype, start, end });
+        true
+    }
+
+    /// 解锁区间可能把旧锁拆成两段
+    fn unlock_range(&mut self, owner : usize, start : usize, end : usize) {
+        let mut rt = Vec::new();
+        for l in self.range.iter() {
+            if l.owner != owner || !l.overlap(start, end) {
+                rt.push(*l);
+                continue;
+            }
+            if l.start < start {
+                rt.push(RangeLock { end : start, ..*l });
+            }
+            if end < l.end {
+                rt.push(RangeLock { start : end, ..*l });
+            }
+        }
+        self.range = rt;
+    }
+
+    fn release(&mut self, owner : usize) {
+        self.funlock(owner);
+        self.range.retain(|l| l.owner != owner);
+    }
+}
+
+/// ## 锁管理
+/// 以文件 ID 区分文件，内部加锁，等待者睡眠直到有锁被释放
+pub struct LockManager {
+    files : ContentMutex<BTreeMap<usize, FileLock>>,
+    /// 每次释放锁时增加，等待者以此判断入睡前是否错过了释放
+    released : AtomicUsize,
+    waiter : &'static dyn Wait,
+}
+
+impl LockManager {
+    pub fn new(waiter : &'static dyn Wait)->Self {
+        Self {
+            files : ContentMutex::new(BTreeMap::new()),
+            released : AtomicUsize::new(0),
+            waiter,
+        }
+    }
+
+    /// 成功时可能降级了原有的锁，同样唤醒等待者
+    pub fn try_flock(&self, file_id : usize, owner : usize, ltype : LockType)->bool {
+        let mut files = self.files.lock();
+        let rt = files.entry(file_id).or_insert(FileLock::new()).try_flock(owner, ltype);
+        drop(files);
+        if rt {
+            self.wake();
+        }
+        rt
+    }
+
+    /// 被打断时返回 false
+    pub fn flock(&self, file_id : usize, owner : usize, ltype : LockType)->bool {
+        self.block(|| self.try_flock(file_id, owner, ltype))
+    }
+
+    pub fn funlock(&self, file_id : usize, owner : usize) {
+        self.modify(file_id, |lock| lock.funlock(owner));
+    }
+
+    /// len 为 0 表示锁到文件末尾
+    pub fn try_lock_range(&self, file_id : usize, owner : usize, ltype : LockType,
+            start : usize, len : usize)->bool {
+        let mut files = self.files.lock();
+        let rt = files.entry(file_id).or_insert(FileLock::new())
+            .try_lock_range(owner, ltype, start, Self::end(start, len));
+        drop(files);
+        if rt {
+            self.wake();
+        }
+        rt
+    }
+
+    /// 被打断时返回 false
+    pub fn lock_range(&self, file_id : usize, owner : usize, ltype : LockType,
+            start : usize, len : usize)->bool {
+        self.block(|| self.try_lock_range(file_id, owner, ltype, start, len))
+    }
+
+    pub fn unlock_range(&self, file_id : usize, owner : usize, start : usize, len : usize) {
+        self.modify(file_id, |lock| lock.unlock_range(owner, start, Self::end(start, len)));
+    }
+
+    /// 任务关闭文件时释放其在该文件上的所有锁
+    pub fn release(&self, file_id : usize, owner : usize) {
+        self.modify(file_id, |lock| lock.release(owner));
+    }
+
+    /// 任务退出时释放其所有锁
+    pub fn release_task(&self, owner : usize) {
+        let mut files = self.files.lock();
+        let mut empty = Vec::new();
+        for (id, lock) in files.iter_mut() {
+            lock.release(owner);
+            if lock.is_empty() {
+                empty.push(*id);
+            }
+        }
+        for id in empty {
+            files.remove(&id);
+        }
+        drop(files);
+        self.wake();
+    }
+
+    /// 先记下释放次数再尝试，尝试失败后只有期间没有释放才入睡
+    fn block<F : Fn()->bool>(&self, try_lock : F)->bool {
+        loop {
+            let seen = self.released.load(Ordering::SeqCst);
+            if try_lock() {
+                return true;
+            }
+            if !self.waiter.wait(&self.released, seen) {
+                return false;
+            }
+        }
+    }
+
+    fn wake(&self) {
+        self.released.fetch_add(1, Ordering::SeqCst);
+        self.waiter.wake(&self.released);
+    }
+
+    fn modify<F : FnOnce(&mut FileLock)>(&self, file_id : usize, f : F) {
+        let mut files = self.files.lock();
+        if let Some(lock) = files.get_mut(&file_id) {
+            f(lock);
+            if lock.is_empty() {
+                files.remove(&file_id);
+            }
+        }
+        drop(files);
+        self.wake();
+    }
+
+    fn end(start : usize, len : usize)->usize {
+        if len == 0 { usize::MAX } else { start.saturating_add(len) }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::sync::atomic::AtomicUsize;
+    use super::*;
+
+    struct NoWait;
+
+    impl Wait for NoWait {
+        fn wait(&self, _word : &AtomicUsize, _expected : usize)->bool {
+            false
+        }
+
+        fn wake(&self, _word : &AtomicUsize) {}
+    }
+
+    static NO_WAIT : NoWait = NoWait;
+
+    fn ranges(lock : &FileLock, owner : usize)->Vec<(usize, usize)> {
+        let mut rt : Vec<(usize, usize)> = lock.range.iter()
+            .filter(|l| l.owner == owner)
+            .map(|l| (l.start, l.end))
+            .collect();
+        rt.sort();
+        rt
+    }
+
+    #[test]
+    fn flock_shared_and_exclusive() {
+        let mut lock = FileLock::new();
+        assert!(lock.try_flock(1, LockType::Shared));
+        assert!(lock.try_flock(2, LockType::Shared));
+        assert!(!lock.try_flock(3, LockType::Exclusive));
+        lock.funlock(2);
+        assert!(!lock.try_flock(3, LockType::Exclusive));
+        lock.funlock(1);
+        assert!(lock.try_flock(3, LockType::Exclusive));
+        assert!(!lock.try_flock(1, LockType::Shared));
+    }
+
+    #[test]
+    fn flock_upgrade_and_downgrade() {
+        let mut lock = FileLock::new();
+        assert!(lock.try_flock(1, LockType::Shared));
+        assert!(lock.try_flock(1, LockType::Exclusive));
+        assert_eq!(lock.whole.len(), 1);
+        assert!(!lock.try_flock(2, LockType::Shared));
+        assert!(lock.try_flock(1, LockType::Shared));
+        assert!(lock.try_flock(2, LockType::Shared));
+        assert!(!lock.try_flock(2, LockType::Exclusive));
+    }
+
+    #[test]
+    fn range_conflicts_only_on_overlap() {
+        let mut lock = FileLock::new();
+        assert!(lock.try_lock_range(1, LockType::Exclusive, 0, 10));
+        assert!(lock.try_lock_range(2, LockType::Exclusive, 10, 20));
+        assert!(!lock.try_lock_range(2, LockType::Shared, 9, 11));
+        assert!(!lock.try_lock_range(1, LockType::Shared, 5, 15));
+        assert!(lock.try_lock_range(3, LockType::Shared, 20, usize::MAX));
+        assert!(!lock.try_lock_range(2, LockType::Exclusive, 100, 101));
+    }
+
+    #[test]
+    fn unlock_splits_range() {
+        let mut lock = FileLock::new();
+        assert!(lock.try_lock_range(1, LockType::Exclusive, 0, 100));
+        lock.unlock_range(1, 40, 60);
+        assert_eq!(ranges(&lock, 1), alloc::vec![(0, 40), (60, 100)]);
+        assert!(lock.try_lock_range(2, LockType::Exclusive, 40, 60));
+        lock.unlock_range(1, 0, 50);
+        assert_eq!(ranges(&lock, 1), alloc::vec![(60, 100)]);
+        lock.unlock_range(1, 90, usize::MAX);
+        assert_eq!(ranges(&lock, 1), alloc::vec![(60, 90)]);
+    }
+
+    #[test]
+    fn relock_replaces_own_range() {
+        let mut lock = FileLock::new();
+        assert!(lock.try_lock_range(1, LockType::Exclusive, 0, 100));
+        assert!(lock.try_lock_range(1, LockType::Shared, 20, 30));
+        assert_eq!(ranges(&lock, 1), alloc::vec![(0, 20), (20, 30), (30, 100)]);
+        assert!(lock.try_lock_range(2, LockType::Shared, 25, 26));
+        assert!(!lock.try_lock_range(2, LockType::Shared, 15, 26));
+    }
+
+    #[test]
+    fn manager_release_task() {
+        let locks = LockManager::new(&NO_WAIT);
+        assert!(locks.try_flock(7, 1, LockType::Exclusive));
+        assert!(locks.try_lock_range(7, 1, LockType::Exclusive, 0, 0));
+        assert!(!locks.try_lock_range(7, 2, LockType::Shared, 1000, 1));
+        assert!(!locks.flock(7, 2, LockType::Shared));
+        locks.release_task(1);
+        assert!(locks.try_flock(7, 2, LockType::Shared));
+        assert!(locks.lock_range(7, 2, LockType::Exclusive, 1000, 1));
+    }
+}
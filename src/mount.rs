@@ -0,0 +1,81 @@
+//! # 挂载选项
+//! 文件系统创建时给出，决定不支持权限位的格式如何呈现权限
+//! MountSource 对应 fstab 中的 LABEL=、UUID=，用于按卷标或 UUID 找到要挂载的卷
+
+use alloc::{prelude::v1::*, sync::Arc};
+use crate::{DiskInfo, Format, Permission};
+
+pub struct MountOption {
+    /// 不支持权限的格式中，所有项归属的用户
+    pub uid : usize,
+    pub gid : usize,
+    /// 默认权限掩码，权限为 0o777 & !umask
+    pub umask : usize,
+    /// 顺序读时最多预读的块数，为 0 时不预读
+    pub read_ahead : usize,
+    pub write_mode : WriteMode,
+    /// 只读挂载，任何写操作都返回 IoError::ReadOnly，也不写回元数据
+    pub read_only : bool,
+    /// 目录缓存的内存预算，单位为字节，超过后收起最久未使用的目录
+    pub node_budget : usize,
+    /// 通配符匹配是否不区分大小写，None 时 FAT32 不区分，其余区分
+    pub case_insensitive : Option<bool>,
+}
+
+impl MountOption {
+    pub fn new()->Self {
+        Self {
+            uid : 0,
+            gid : 0,
+            umask : 0o022,
+            read_ahead : 32,
+            write_mode : WriteMode::WriteBack { max_age : 30 },
+            read_only : false,
+            node_budget : 1 << 20,
+            case_insensitive : None,
+        }
+    }
+
+    pub fn default_permission(&self)->Permission {
+        Permission::new(self.uid, self.gid, 0o777 & !self.umask)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WriteMode {
+    /// 每次写入后立即写回设备
+    WriteThrough,
+    /// 脏数据留在缓冲区中，由 writeback 在超过 max_age 后写回
+    WriteBack { max_age : usize },
+}
+
+/// ## 挂载来源
+pub enum MountSource {
+    Label(String),
+    Uuid(String),
+}
+
+impl MountSource {
+    /// 解析 LABEL=xxx 或 UUID=xxx
+    pub fn parse(spec : &str)->Option<Self> {
+        let (key, value) = spec.trim().split_once('=')?;
+        match key {
+            "LABEL" => Some(Self::Label(value.to_string())),
+            "UUID" => Some(Self::Uuid(value.to_string())),
+            _ => None,
+        }
+    }
+
+    /// UUID 不区分大小写
+    pub fn matches(&self, info : &DiskInfo)->bool {
+        match self {
+            Self::Label(label) => info.label == *label,
+            Self::Uuid(uuid) => info.uuid.eq_ignore_ascii_case(uuid),
+        }
+    }
+
+    /// 在候选的卷中找到第一个匹配的
+    pub fn find(&self, formats : &[Arc<dyn Format>])->Option<Arc<dyn Format>> {
+        formats.iter().find(|f| self.matches(&f.parse_super_block())).cloned()
+    }
+}
@@ -1,137 +1,366 @@
-use alloc::{collections::BTreeMap, prelude::v1::*, sync::Arc};
+use core::mem::size_of;
 
-use crate::{leaf::Leaf, require::Format};
+use alloc::{collections::{BTreeMap, BTreeSet}, prelude::v1::*, sync::Arc};
+use tisu_sync::ContentMutex;
 
+use crate::{DirectoryDiff, NodeCache, leaf::{Leaf, LeafType}, require::Format};
+
+/// 未解析的节点最多记住的不存在的名字
+const MISS_LIMIT : usize = 32;
+
+/// 每个节点单独加锁，沿路径查找时逐级加锁，需要同时持有时先父后子
+pub type NodeRef = Arc<ContentMutex<Node>>;
+
+/// directory 与 file 只通过 insert、update 等方法修改，以保持名字索引一致
 pub struct Node {
     pub name : String,
     pub path : String,
     pub block_idx : usize,
     pub directory : Vec<Leaf>,
     pub file : Vec<Leaf>,
-    pub node : Option<BTreeMap<String, Node>>,
+    /// 经过过的子目录节点，经过时才建立占位，解析时才读取磁盘
+    pub node : Option<BTreeMap<String, NodeRef>>,
+    /// 为 false 时 directory 与 file 只含单独查找到的项，尚未整体解析
+    pub loaded : bool,
+    /// 名字 -> 所在的列表与下标
+    index : BTreeMap<String, (LeafType, usize)>,
+    /// 最近确认不存在的名字，新建、改名加入或重新解析时失效
+    misses : Vec<String>,
 }
 
 impl Node {
-    pub fn init(&mut self, format: &mut dyn Format) {
-        let leaf = format.parse_node(self.block_idx).unwrap();
+    pub fn init(&mut self, format: &mut dyn Format)->Result<(), NodeError> {
+        let leaf = format.parse_node(self.block_idx).map_err(|_| NodeError::ExpendErr)?;
         for l in leaf {
-            if l.is_directory() {
-                self.directory.push(l);
+            self.push(l);
+        }
+        Ok(())
+    }
+
+    /// 取得目录节点，path 以 '/' 结尾，为空时即 node 本身
+    /// 经过的每一级节点都记入目录缓存，只解析路径上的目录
+    pub fn walk(node : &NodeRef, path : &str, format : Arc<dyn Format>, cache : &NodeCache)
+            ->Result<NodeRef, NodeError> {
+        Self::walk_checked(node, path, format, cache, |_| true)
+    }
+
+    /// 同 walk，进入每一级目录前以 check 检查其项，不通过时停止
+    /// 只需一次遍历即可检查路径上所有目录的权限
+    pub fn walk_checked<F : Fn(&Leaf)->bool>(node : &NodeRef, path : &str, format : Arc<dyn Format>,
+            cache : &NodeCache, check : F)->Result<NodeRef, NodeError> {
+        let mut cur = node.clone();
+        let mut rest = path;
+        while rest.len() > 0 {
+            let prefix = &path[..path.len() - rest.len()];
+            let (name, p) = rest.split_once("/").unwrap();
+            let next = {
+                let mut node = cur.lock();
+                let (leaf, cost) = node.lookup(name, format.clone())?;
+                cache.charge(prefix, cost);
+                if leaf.map_or(false, |l| !check(&l)) {
+                    return Err(NodeError::Denied(name.to_string()));
+                }
+                let (next, cost) = node.child(name)?;
+                cache.touch(prefix, cost);
+                next
+            };
+            cur = next;
+            rest = p;
+        }
+        Ok(cur)
+    }
+
+    /// 只沿已展开的节点查找，不解析磁盘
+    pub fn find(node : &NodeRef, path : &str)->Option<NodeRef> {
+        let mut cur = node.clone();
+        let mut rest = path;
+        while rest.len() > 0 {
+            let (name, p) = rest.split_once("/").unwrap();
+            let next = cur.lock().node.as_ref()?.get(name)?.clone();
+            cur = next;
+            rest = p;
+        }
+        Some(cur)
+    }
+
+    /// 在已展开的节点中找到起始块为 block_idx 的目录，逐个加锁，不解析磁盘
+    pub fn locate(node : &NodeRef, block_idx : usize)->Option<NodeRef> {
+        let children : Vec<NodeRef> = {
+            let cur = node.lock();
+            if cur.block_idx == block_idx {
+                return Some(node.clone());
             }
-            else {
-                self.file.push(l);
+            match &cur.node {
+                Some(nodes) => nodes.values().cloned().collect(),
+                None => Vec::new(),
             }
+        };
+        children.iter().find_map(|child| Self::locate(child, block_idx))
+    }
+
+    pub fn search_leaf(node : &NodeRef, path : String, format : Arc<dyn Format>, cache : &NodeCache)
+            ->Result<Leaf, NodeError> {
+        let (parent, name) = match path.rsplit_once("/") {
+            Some((parent, name)) => (parent.to_string() + "/", name),
+            None => (String::new(), &path[..]),
+        };
+        let node = Self::walk(node, &parent[..], format.clone(), cache)?;
+        let mut node = node.lock();
+        let (leaf, cost) = node.lookup(name, format)?;
+        cache.charge(&parent[..], cost);
+        leaf.ok_or(NodeError::NoDirectory(path.clone()))
+    }
+
+    pub fn search_node(node : &NodeRef, path : String, format : Arc<dyn Format>, cache : &NodeCache)
+            ->Result<Node, NodeError> {
+        let node = Self::walk(node, &path[..], format.clone(), cache)?;
+        let mut node = node.lock();
+        let cost = node.load(format)?;
+        cache.charge(&path[..], cost);
+        Ok(Node {
+            name : node.name.clone(),
+            path : node.path.clone(),
+            block_idx : node.block_idx,
+            directory : node.directory.clone(),
+            file : node.file.clone(),
+            node : None,
+            loaded : true,
+            index : node.index.clone(),
+            misses : Vec::new(),
+        })
+    }
+
+    pub fn get(&self, name : &str)->Option<&Leaf> {
+        match self.index.get(name)? {
+            (LeafType::Directory, idx) => self.directory.get(*idx),
+            (LeafType::File, idx) => self.file.get(*idx),
         }
     }
 
-    pub fn search_leaf(&mut self, path : String, format : Arc<dyn Format>)->Result<Leaf, NodeError> {
-        if !path.contains("/") {
-            if let Some(dir) = self.directory.iter().find(|d|{d.name == path}) {
-                return Ok(dir.clone());
+    /// 按名字查找项，未解析的节点先使用格式的单项查找，格式不支持时整体解析
+    /// 同时返回新增的占用。已解析的节点直接在索引中查找
+    /// 未解析的节点记录不存在的名字，再次查找时不必访问格式
+    /// 整体解析失败时返回 ExpendErr
+    pub fn lookup(&mut self, name : &str, format : Arc<dyn Format>)->Result<(Option<Leaf>, usize), NodeError> {
+        if let Some(leaf) = self.get(name) {
+            return Ok((Some(leaf.clone()), 0));
+        }
+        if self.loaded || self.misses.iter().any(|n| n == name) {
+            return Ok((None, 0));
+        }
+        let (leaf, cost) = match format.lookup(self.block_idx, name) {
+            Ok(Some(leaf)) => {
+                let cost = size_of::<Leaf>() + leaf.name.len() * 2;
+                self.push(leaf.clone());
+                (Some(leaf), cost)
             }
-            else if let Some(file) = self.file.iter().find(|f|{f.name == path}) {
-                return Ok(file.clone());
+            Ok(None) => (None, 0),
+            Err(_) => {
+                let cost = self.load(format)?;
+                (self.get(name).cloned(), cost)
             }
-            else {
-                return Err(NodeError::NoDirectory(path));
+        };
+        if leaf.is_none() && !self.loaded {
+            if self.misses.len() >= MISS_LIMIT {
+                self.misses.remove(0);
             }
+            self.misses.push(name.to_string());
         }
-        let (name, p) = path.split_once("/").unwrap();
-        for dir in self.directory.iter_mut() {
-            if dir.name == *name {
-                self.expend(format.clone());
-                if let Some(node) = &mut self.node {
-                    return node.get_mut(name).unwrap().search_leaf(p.to_string(), format);
-                }
-                return Err(NodeError::ExpendErr);
-            }
+        Ok((leaf, cost))
+    }
+
+    /// 取得名为 name 的子目录节点，没有时建立占位，同时返回占位的占用
+    pub fn child(&mut self, name : &str)->Result<(NodeRef, usize), NodeError> {
+        let block_idx = match self.get(name) {
+            Some(leaf) if leaf.is_directory() => leaf.block_idx,
+            _ => return Err(NodeError::NoDirectory(name.to_string())),
+        };
+        let path = self.path.clone() + name + "/";
+        let nodes = self.node.get_or_insert_with(BTreeMap::new);
+        if let Some(node) = nodes.get(name) {
+            return Ok((node.clone(), 0));
         }
-        Err(NodeError::NoDirectory("s".to_string() + &path[..] + " " + name + " " + p))
-    }
-
-    pub fn search_node(&mut self, path : String, format : Arc<dyn Format>)->Result<Node, NodeError> {
-        if path.len() == 0 {
-            self.expend(format);
-            return Ok(Node {
-                name : self.name.clone(),
-                path : self.path.clone(),
-                block_idx : self.block_idx,
-                directory : self.directory.clone(),
-                file : self.file.clone(),
-                node : None,
-            });
-        }
-        let (name, p) = path.split_once("/").unwrap();
-        for dir in self.directory.iter_mut() {
-            if dir.name == *name {
-                self.expend(format.clone());
-                if let Some(node) = &mut self.node {
-                    return node.get_mut(name).unwrap().search_node(p.to_string(), format);
-                }
-                return Err(NodeError::ExpendErr);
-            }
+        let node = Node::placeholder(name.to_string(), path, block_idx);
+        let cost = node.cost();
+        let node = Arc::new(ContentMutex::new(node));
+        nodes.insert(name.to_string(), node.clone());
+        Ok((node, cost))
+    }
+
+    /// 加入新建或改名移入的项
+    pub fn insert(&mut self, leaf : Leaf) {
+        self.misses.retain(|n| *n != leaf.name);
+        self.push(leaf);
+    }
+
+    /// 移除一项，子目录节点一同丢弃
+    pub fn remove(&mut self, name : &str)->Option<Leaf> {
+        let (ltype, idx) = self.index.remove(name)?;
+        let list = match ltype {
+            LeafType::Directory => &mut self.directory,
+            LeafType::File => &mut self.file,
+        };
+        let leaf = list.swap_remove(idx);
+        if idx < list.len() {
+            self.index.insert(list[idx].name.clone(), (ltype, idx));
         }
-        Err(NodeError::NoDirectory("s".to_string() + &path[..] + " " + name + " " + p))
+        if let Some(node) = &mut self.node {
+            node.remove(name);
+        }
+        Some(leaf)
     }
 
-    fn expend(&mut self, format : Arc<dyn Format>) {
-        if self.node.is_none() {
-            let mut nodes = BTreeMap::new();
-            for dir in self.directory.iter() {
-                let path = self.path.clone() + &dir.name[..];
-                nodes.insert(dir.name.clone(),
-                Node::new(dir.name.clone(), path, dir.block_idx,
-                    format.parse_node(dir.block_idx).unwrap()));
+    /// 替换同名项
+    pub fn update(&mut self, leaf : Leaf) {
+        if let Some((ltype, idx)) = self.index.get(&leaf.name).cloned() {
+            if ltype != leaf.ltype {
+                return;
+            }
+            match ltype {
+                LeafType::Directory => self.directory[idx] = leaf,
+                LeafType::File => self.file[idx] = leaf,
             }
-            self.node = Some(nodes)
         }
     }
 
-    pub fn refresh(&mut self, path:String, format : Arc<dyn Format>)->Result<(), NodeError> {
-        if path.len() == 0 {
-            self.reset(format);
-            return Ok(())
+    fn push(&mut self, leaf : Leaf) {
+        let list = if leaf.is_directory() { &mut self.directory } else { &mut self.file };
+        self.index.insert(leaf.name.clone(), (leaf.ltype, list.len()));
+        list.push(leaf);
+    }
+
+    fn clear(&mut self) {
+        self.directory.clear();
+        self.file.clear();
+        self.index.clear();
+        self.misses.clear();
+    }
+
+    /// 整体解析，丢弃单独查找到的项，返回增加的占用，已解析时为 0
+    /// 解析失败时保持原样
+    pub fn load(&mut self, format : Arc<dyn Format>)->Result<usize, NodeError> {
+        if self.loaded {
+            return Ok(0);
         }
-        let (name, path) = path.split_once("/").unwrap();
-        for dir in self.directory.iter_mut() {
-            if dir.name == *name {
-                self.expend(format.clone());
-                if let Some(node) = &mut self.node {
-                    return node.get_mut(name).unwrap().refresh(path.to_string(), format);
-                }
-                return Err(NodeError::ExpendErr);
+        let leaves = format.parse_node(self.block_idx).map_err(|_| NodeError::ExpendErr)?;
+        let before = self.cost();
+        self.clear();
+        for l in leaves {
+            self.push(l);
+        }
+        self.loaded = true;
+        Ok(self.cost().saturating_sub(before))
+    }
+
+    /// 丢弃子节点，有子节点仍处于展开状态时不收起
+    pub fn collapse(&mut self)->bool {
+        if let Some(node) = &self.node {
+            if node.values().any(|n| n.lock().node.is_some()) {
+                return false;
             }
         }
-        Err(NodeError::NoDirectory(path.to_string()))
+        self.node = None;
+        true
     }
 
-    pub fn reset(&mut self, format : Arc<dyn Format>) {
-        let leaves = format.parse_node(self.block_idx).unwrap();
+    /// 节点本身大致占用的内存，名字在列表与索引中各存一份
+    pub fn cost(&self)->usize {
+        let leaf : usize = self.directory.iter().chain(self.file.iter())
+            .map(|l| size_of::<Leaf>() + l.name.len() * 2)
+            .sum();
+        size_of::<Self>() + self.name.len() + self.path.len() + leaf
+    }
+
+    /// 被移除或起始块改变的子目录节点已被丢弃，其在目录缓存中的记录一同失效
+    /// 同时返回节点的起始块号，供产生事件时使用
+    pub fn refresh(node : &NodeRef, path : String, format : Arc<dyn Format>, cache : &NodeCache)
+            ->Result<(DirectoryDiff, usize), NodeError> {
+        let node = Self::walk(node, &path[..], format.clone(), cache)?;
+        let (diff, block_idx) = {
+            let mut node = node.lock();
+            let diff = node.reset(format)?;
+            (diff, node.block_idx)
+        };
+        let moved = diff.changed.iter()
+            .filter(|(old, new)| old.is_directory() && old.block_idx != new.block_idx)
+            .map(|(old, _)| old);
+        for leaf in diff.removed.iter().filter(|l| l.is_directory()).chain(moved) {
+            cache.forget(&(path.clone() + &leaf.name[..] + "/")[..]);
+        }
+        Ok((diff, block_idx))
+    }
+
+    /// 重新解析并与缓存的项比较，只调整有变化的项，其余子目录节点保持展开
+    /// 起始块改变的子目录丢弃其节点。未整体解析过的节点没有可比较的内容，不产生变化
+    /// 解析失败时保持原样
+    pub fn reset(&mut self, format : Arc<dyn Format>)->Result<DirectoryDiff, NodeError> {
+        let leaves = format.parse_node(self.block_idx).map_err(|_| NodeError::ExpendErr)?;
+        let mut diff = DirectoryDiff::new();
+        if !self.loaded {
+            self.clear();
+            for leaf in leaves {
+                self.push(leaf);
+            }
+            self.loaded = true;
+            return Ok(diff);
+        }
+        self.misses.clear();
+        let names : BTreeSet<String> = leaves.iter().map(|l| l.name.clone()).collect();
+        let stale : Vec<String> = self.index.keys()
+            .filter(|n| !names.contains(*n))
+            .cloned()
+            .collect();
+        for name in stale {
+            diff.removed.push(self.remove(&name[..]).unwrap());
+        }
         for leaf in leaves {
-            match leaf.ltype {
-                crate::leaf::LeafType::File => self.file.push(leaf),
-                crate::leaf::LeafType::Directory => {
-                    self.directory.push(leaf);
+            match self.get(&leaf.name[..]).cloned() {
+                None => {
+                    diff.added.push(leaf.clone());
+                    self.push(leaf);
+                }
+                Some(old) if old.ltype != leaf.ltype => {
+                    self.remove(&old.name[..]);
+                    diff.removed.push(old);
+                    diff.added.push(leaf.clone());
+                    self.push(leaf);
                 }
+                Some(old) if !old.same(&leaf) => {
+                    if old.is_directory() && old.block_idx != leaf.block_idx {
+                        if let Some(node) = &mut self.node {
+                            node.remove(&leaf.name);
+                        }
+                    }
+                    diff.changed.push((old, leaf.clone()));
+                    self.update(leaf);
+                }
+                Some(_) => {}
             }
         }
-        self.node = None;
+        Ok(diff)
     }
 
     pub fn new(name : String, path: String, block_idx: usize, leaf : Vec<Leaf>)->Self {
-        let mut file = Vec::new();
-        let mut directory = Vec::new();
+        let mut node = Self::placeholder(name, path, block_idx);
         for l in leaf {
-            if l.is_directory() { directory.push(l) }
-            else { file.push(l) }
+            node.push(l);
         }
+        node.loaded = true;
+        node
+    }
+
+    /// 尚未解析的子目录
+    pub fn placeholder(name : String, path : String, block_idx : usize)->Self {
         Self {
             name,
             path,
             block_idx,
-            directory,
-            file,
-            node: None,
+            directory : Vec::new(),
+            file : Vec::new(),
+            node : None,
+            loaded : false,
+            index : BTreeMap::new(),
+            misses : Vec::new(),
         }
     }
 }
@@ -145,5 +374,7 @@ pub enum ItemType {
 pub enum NodeError {
     NoFile(String),
     NoDirectory(String),
+    /// 路径上的目录没有通过检查
+    Denied(String),
     ExpendErr,
-}
\ No newline at end of file
+}
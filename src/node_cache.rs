@@ -0,0 +1,132 @@
+//! # 目录缓存
+//! 记录已展开的目录节点及其子节点的占用，超过预算时按最近使用时间收起节点
+//! 只收起没有已展开子目录的节点，收起后子节点整体丢弃，再次经过时重新从磁盘解析
+//! 有打开文件的目录被钉住，其父目录不会被收起
+//! 路径与 Node::walk 的参数一致，以 '/' 结尾，根目录为空
+
+use core::ops::Bound::{Excluded, Unbounded};
+
+use alloc::{collections::BTreeMap, prelude::v1::*};
+use tisu_sync::ContentMutex;
+
+pub struct NodeCache {
+    /// 内存预算，单位为字节
+    pub budget : usize,
+    inner : ContentMutex<NodeCacheInner>,
+}
+
+struct NodeCacheInner {
+    tick : usize,
+    used : usize,
+    /// 已展开节点的路径 -> (最近使用时间, 子节点占用)
+    expended : BTreeMap<String, (usize, usize)>,
+    /// 目录路径 -> 其中打开的文件数
+    pinned : BTreeMap<String, usize>,
+}
+
+impl NodeCache {
+    pub fn new(budget : usize)->Self {
+        Self {
+            budget,
+            inner : ContentMutex::new(NodeCacheInner {
+                tick : 0,
+                used : 0,
+                expended : BTreeMap::new(),
+                pinned : BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// 记录一次经过已展开的节点，cost 为这次新解析的子节点的占用
+    pub fn touch(&self, path : &str, cost : usize) {
+        let mut inner = self.inner.lock();
+        let inner = &mut *inner;
+        inner.tick += 1;
+        let entry = inner.expended.entry(path.to_string()).or_insert((0, 0));
+        entry.0 = inner.tick;
+        entry.1 += cost;
+        inner.used += cost;
+    }
+
+    /// 节点 path 新解析的占用计入其父节点，收起父节点时一同释放，根目录不计
+    pub fn charge(&self, path : &str, cost : usize) {
+        if cost == 0 || path.len() == 0 {
+            return;
+        }
+        let parent = match path[..path.len() - 1].rfind('/') {
+            Some(idx) => &path[..idx + 1],
+            None => "",
+        };
+        self.touch(parent, cost);
+    }
+
+    pub fn used(&self)->usize {
+        self.inner.lock().used
+    }
+
+    pub fn pin(&self, path : &str) {
+        *self.inner.lock().pinned.entry(path.to_string()).or_insert(0) += 1;
+    }
+
+    pub fn unpin(&self, path : &str) {
+        let mut inner = self.inner.lock();
+        if let Some(count) = inner.pinned.get_mut(path) {
+            *count -= 1;
+            if *count == 0 {
+                inner.pinned.remove(path);
+            }
+        }
+    }
+
+    /// 按最近使用时间从旧到新给出需要收起的节点，直到占用回到预算以内
+    pub fn victims(&self)->Vec<String> {
+        let inner = self.inner.lock();
+        if inner.used <= self.budget {
+            return Vec::new();
+        }
+        let mut candidates : Vec<(usize, usize, &String)> = inner.expended.iter()
+            .filter(|(path, _)| !Self::has_under(&inner.expended, path) &&
+                !Self::has_under(&inner.pinned, path))
+            .map(|(path, (tick, cost))| (*tick, *cost, path))
+            .collect();
+        candidates.sort();
+        let mut over = inner.used - self.budget;
+        let mut rt = Vec::new();
+        for (_, cost, path) in candidates {
+            if over == 0 {
+                break;
+            }
+            over = over.saturating_sub(cost);
+            rt.push(path.clone());
+        }
+        rt
+    }
+
+    /// 节点已收起
+    pub fn remove(&self, path : &str) {
+        let mut inner = self.inner.lock();
+        if let Some((_, cost)) = inner.expended.remove(path) {
+            inner.used -= cost;
+        }
+    }
+
+    /// 节点被重新解析后，其本身与下面所有的记录失效
+    pub fn forget(&self, path : &str) {
+        let mut inner = self.inner.lock();
+        let keys : Vec<String> = inner.expended.keys()
+            .filter(|p| p.starts_with(path))
+            .cloned()
+            .collect();
+        for key in keys {
+            let (_, cost) = inner.expended.remove(&key).unwrap();
+            inner.used -= cost;
+        }
+    }
+
+    /// 子孙路径紧跟在自身之后排列，只需检查下一项
+    fn has_under<V>(map : &BTreeMap<String, V>, path : &String)->bool {
+        map.range::<String, _>((Excluded(path), Unbounded))
+            .next()
+            .map_or(false, |(p, _)| p.starts_with(&path[..]))
+    }
+}
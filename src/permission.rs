@@ -0,0 +1,86 @@
+//! # 权限
+//! 仿照 POSIX 的 rwx 权限位，按所有者、组、其他人三级检查
+//! 支持权限位的格式在 Leaf 中给出，其余格式（如 FAT32）使用挂载时的默认权限
+
+use alloc::prelude::v1::*;
+
+/// ## 身份
+/// 调用者在打开文件、进入目录时提供，用于权限检查
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub uid : usize,
+    pub gid : usize,
+    /// 附加组
+    pub groups : Vec<usize>,
+}
+
+impl Identity {
+    pub fn new(uid : usize, gid : usize)->Self {
+        Self {
+            uid,
+            gid,
+            groups : Vec::new(),
+        }
+    }
+
+    pub fn root()->Self {
+        Self::new(0, 0)
+    }
+
+    pub fn is_root(&self)->bool {
+        self.uid == 0
+    }
+
+    pub fn in_group(&self, gid : usize)->bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    Read = 4,
+    Write = 2,
+    Execute = 1,
+}
+
+impl Access {
+    pub fn val(self)->usize {
+        self as usize
+    }
+}
+
+/// ## 权限
+/// mode 只使用低 9 位，即 rwxrwxrwx
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Permission {
+    pub uid : usize,
+    pub gid : usize,
+    pub mode : usize,
+}
+
+impl Permission {
+    pub fn new(uid : usize, gid : usize, mode : usize)->Self {
+        Self {
+            uid,
+            gid,
+            mode : mode & 0o777,
+        }
+    }
+
+    /// root 不受读写限制，但执行至少需要一个 x 位
+    pub fn allow(&self, identity : &Identity, access : Access)->bool {
+        if identity.is_root() {
+            return access != Access::Execute || self.mode & 0o111 != 0;
+        }
+        let bits = if identity.uid == self.uid {
+            (self.mode >> 6) & 7
+        }
+        else if identity.in_group(self.gid) {
+            (self.mode >> 3) & 7
+        }
+        else {
+            self.mode & 7
+        };
+        bits & access.val() != 0
+    }
+}
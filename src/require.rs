@@ -1,40 +1,212 @@
-use crate::{Directory, File, FileFlag, disk_info::DiskInfo, leaf::Leaf, system::{IoError, IoResult}};
+use crate::{Directory, DirectoryDiff, DirectoryItem, EventMask, Extent, File, Identity, ListOption, LockType, OpenFile, OpenFlag, ShareMode, SpaceInfo, StatFs, WatchEvent, WatchTarget, disk_info::DiskInfo, leaf::{Leaf, LeafType}, system::{IoError, IoResult}};
+use core::sync::atomic::AtomicUsize;
 use alloc::prelude::v1::*;
+use device_buffer::CacheBuffer;
 
-pub trait Format {
+/// 格式在多个核之间共享，需要 Send + Sync
+pub trait Format : Send + Sync {
     fn parse_node(&self, block_idx : usize)->Result<Vec<Leaf>, ()>;
     fn get_block_chain(&self, start_idx : usize)->Result<Vec<usize>, ()>;
     fn parse_super_block(&self)->DiskInfo;
     fn get_device(&self)->usize;
+
+    /// 修改卷标，只读格式不需要实现
+    fn set_label(&self, _label : &str)->Result<(), ()> {
+        Err(())
+    }
+
+    /// 根据位图或 FAT 表统计空闲空间
+    fn statfs(&self)->Result<SpaceInfo, ()> {
+        Err(())
+    }
+
+    /// 将格式自身缓存的元数据写回，例如 FAT 表与 FSInfo
+    fn sync(&self)->Result<(), ()> {
+        Ok(())
+    }
+
+    /// 卸载前调用，写回元数据并清除脏标志
+    fn unmount(&self)->Result<(), ()> {
+        self.sync()
+    }
+
+    /// 以连续区间给出文件占用的块，能直接得到区间的格式应当覆盖此实现
+    fn get_extents(&self, start_idx : usize)->Result<Vec<Extent>, ()> {
+        Ok(Extent::merge(&self.get_block_chain(start_idx)?[..]))
+    }
+
+    /// 在 dir_idx 所指目录中只查找一个名字，不存在时返回 Ok(None)
+    /// 供有磁盘索引的格式（如 ext2 的 htree）实现，默认不支持，此时整个目录被解析
+    fn lookup(&self, _dir_idx : usize, _name : &str)->Result<Option<Leaf>, ()> {
+        Err(())
+    }
+
+    /// 在 dir_idx 所指目录中新建一项，返回新项。只读格式不需要实现
+    fn create(&self, _dir_idx : usize, _name : &str, _ltype : LeafType)->Result<Leaf, ()> {
+        Err(())
+    }
+
+    /// 将 dir_idx 所指目录中的 leaf 移动到 new_dir_idx 所指目录并改名为 new_name
+    /// 两个目录可以相同，返回移动后的项
+    fn rename(&self, _dir_idx : usize, _leaf : &Leaf, _new_dir_idx : usize, _new_name : &str)
+            ->Result<Leaf, ()> {
+        Err(())
+    }
+
+    /// 改变文件大小，按需分配或释放块，返回更新后的项，起始块可能改变
+    fn resize(&self, _dir_idx : usize, _leaf : &Leaf, _size : usize)->Result<Leaf, ()> {
+        Err(())
+    }
+}
+
+/// 缓冲区写回，由持有缓冲区的一方实现
+pub trait Flush {
+    /// 将设备上 [addr, addr + len) 范围内的脏数据写回
+    fn flush(&mut self, device_id : usize, addr : usize, len : usize);
+
+    /// 将设备上所有脏数据写回
+    fn flush_all(&mut self, device_id : usize);
 }
 
+/// 预读，由持有缓冲区的一方实现
+pub trait Prefetch {
+    /// 将设备上 [addr, addr + len) 范围内的块读入缓冲区，数据不交给调用者
+    fn prefetch(&mut self, device_id : usize, addr : usize, len : usize);
+}
+
+/// 文件系统使用的缓冲区，由文件系统内部加锁，只需要 Send
+pub trait Cache : CacheBuffer + Flush + Prefetch + Send {}
+
+impl<T : CacheBuffer + Flush + Prefetch + Send> Cache for T {}
+
+/// 阻塞与唤醒，由调度器实现，语义与 futex 一致
+pub trait Wait : Send + Sync {
+    /// word 仍等于 expected 时让当前任务睡眠，直到 word 上的 wake
+    /// 比较与入睡对 wake 是原子的，返回 false 表示被信号等打断
+    fn wait(&self, word : &AtomicUsize, expected : usize)->bool;
+
+    /// 唤醒所有在 word 上睡眠的任务
+    fn wake(&self, word : &AtomicUsize);
+}
+
+/// 所有操作只需要 &self，实现者内部加锁，可以在多个核上同时调用
 pub trait SystemOp {
-    fn file(&mut self, id : usize)->Option<&mut File>;
+    fn file(&self, id : usize)->Option<File>;
+
+    /// 以 identity 的身份打开文件，路径上的目录需要执行权限
+    /// 每次打开都产生新的打开文件描述，由调用者放入任务的描述符表
+    /// 创建、截断等行为由 flag 决定，在一次调用内完成，flag 无效时返回 InvalidFlag
+    /// 同一文件的多次打开按 share 计数，task_id 成为文件的所有者之一
+    fn open(&self, task_id : usize, path : String, flag : OpenFlag, share : ShareMode,
+        identity : &Identity)->Result<OpenFile, IoError>;
+
+    /// 描述符表释放最后一个引用后调用，最后一次打开关闭后文件才关闭
+    fn close(&self, file : OpenFile);
 
-    fn open(&mut self, path : String, flag : FileFlag)->Result<&mut File, ()>;
+    /// 仅取得目录信息，需要目录的读权限
+    fn enter(&self, path : String, identity : &Identity)->Result<Directory, IoError>;
 
-    fn close(&mut self, id : usize);
+    /// 同 enter，按 option 过滤与排序
+    fn enter_with(&self, path : String, option : &ListOption, identity : &Identity)
+        ->Result<Directory, IoError>;
 
-    /// 仅取得目录信息
-    fn enter(&mut self, path : String)->Result<Directory, ()>;
+    /// 只取得名字与 pattern 匹配的项，pattern 含有 '/' 时匹配其下各级的相对路径
+    /// 遍历中无法进入的子目录被跳过
+    fn enter_glob(&self, path : String, pattern : &str, identity : &Identity)->Result<Directory, IoError>;
 
-    /// 取得文件信息
-    fn get_file(&mut self, path : String)->Result<File, IoError>;
+    /// 取得文件的大小、时间与属性，不分配文件 ID，列目录时直接使用 DirectoryItem 中的信息即可
+    fn get_file(&self, path : String)->Result<DirectoryItem, IoError>;
 
-    fn read(&mut self, id : usize, data : &mut [u8])->IoResult;
+    /// 移动或改名，两个父目录都需要写权限，目标已存在时失败，与原路径相同时不做改变
+    /// 已打开的文件随之改变路径，目录下有打开的文件时返回 Busy
+    fn rename(&self, old : String, new : String, identity : &Identity)->Result<(), IoError>;
 
-    fn write(&mut self, id : usize, data : &[u8])->IoResult;
+    /// 从打开文件描述的当前位置读取，并推进位置
+    fn read(&self, file : &OpenFile, data : &mut [u8])->IoResult;
+
+    fn write(&self, file : &OpenFile, data : &[u8])->IoResult;
 
     fn total_size(&self)->usize;
 
+    /// 总量、空闲与可用的块和文件数，以及卷标等信息
+    fn statfs(&self)->Result<StatFs, IoError>;
+
     fn block_size(&self)->usize;
 
+    /// 卷标、UUID 与格式版本
+    fn label(&self)->String;
+
+    fn uuid(&self)->String;
+
+    fn version(&self)->usize;
+
+    fn set_label(&self, label : &str)->Result<(), IoError>;
+
     /// 判断此文件系统是否包含该文件 ID
     fn contain(&self, id : usize)->bool;
 
-    /// 刷新对应目录下的信息
-    fn refresh(&mut self, dir : &Directory);
+    /// 重新读取目录并返回与缓存相比的变化，打开的文件随之更新大小
+    /// 已不存在的打开文件被标记为删除，之后对其读写返回 IoError::NotFound
+    fn refresh(&self, dir : &Directory)->Result<DirectoryDiff, IoError>;
 
     fn check(&self)->usize;
+
+    /// 将文件的数据与所在目录项写回设备
+    fn fsync(&self, file : &OpenFile)->Result<(), IoError>;
+
+    /// 只将文件的数据写回设备
+    fn fdatasync(&self, file : &OpenFile)->Result<(), IoError>;
+
+    /// 将整个文件系统写回设备
+    fn sync(&self)->Result<(), IoError>;
+
+    /// 写回模式下由定时器调用，写回脏了 max_age 以上的块，now 的单位与 max_age 一致
+    fn writeback(&self, now : usize);
+
+    /// 卸载文件系统，有文件打开时返回 IoError::Busy，force 为真时强制关闭
+    /// 成功后所有句柄都被回收，之后的操作都返回 IoError::Unmounted，只有卷的信息仍可读取
+    fn unmount(&self, force : bool)->Result<(), IoError>;
+
+    /// 切换只读与读写，切换为只读时有文件以写方式打开则返回 IoError::Busy
+    fn remount(&self, read_only : bool)->Result<(), IoError>;
+
+    fn read_only(&self)->bool;
+
+    /// 整文件锁，冲突时返回 IoError::WouldBlock
+    fn try_flock(&self, file : &OpenFile, ltype : LockType)->Result<(), IoError>;
+
+    /// 整文件锁，冲突时睡眠等待，被打断时返回 IoError::Interrupted
+    fn flock(&self, file : &OpenFile, ltype : LockType)->Result<(), IoError>;
+
+    fn funlock(&self, file : &OpenFile)->Result<(), IoError>;
+
+    /// 区间锁，len 为 0 表示锁到文件末尾，冲突时返回 IoError::WouldBlock
+    fn try_lock_range(&self, file : &OpenFile, ltype : LockType, start : usize, len : usize)
+        ->Result<(), IoError>;
+
+    /// 区间锁，冲突时睡眠等待，被打断时返回 IoError::Interrupted
+    fn lock_range(&self, file : &OpenFile, ltype : LockType, start : usize, len : usize)
+        ->Result<(), IoError>;
+
+    fn unlock_range(&self, file : &OpenFile, start : usize, len : usize)->Result<(), IoError>;
+
+    /// 任务退出时释放其持有的所有锁
+    fn release_locks(&self, task_id : usize);
+
+    /// 在路径或目录上登记监视，对象需要存在且可读，返回监视描述符
+    /// 以起始块号指定的目录需要已在目录缓存中，否则返回 NotFound
+    fn add_watch(&self, task_id : usize, target : WatchTarget, mask : EventMask,
+        identity : &Identity)->Result<usize, IoError>;
+
+    fn remove_watch(&self, wd : usize)->Result<(), IoError>;
+
+    /// 取出积压的事件，没有事件时返回空
+    fn read_events(&self, wd : usize)->Result<Vec<WatchEvent>, IoError>;
+
+    /// 睡眠到至少有一个事件，被打断时返回 IoError::Interrupted，监视被移除时返回 NotFound
+    fn wait_events(&self, wd : usize)->Result<Vec<WatchEvent>, IoError>;
+
+    /// 任务退出时移除其所有监视
+    fn release_watches(&self, task_id : usize);
 }
 
@@ -0,0 +1,34 @@
+//! # 空间统计
+//! 格式根据位图或 FAT 表统计空闲空间，文件系统补充块大小等信息后交给外界
+
+use alloc::prelude::v1::*;
+
+/// ## 格式统计
+/// 由 Format::statfs 给出
+pub struct SpaceInfo {
+    /// 数据区的块数，不含保留区、FAT 表、位图等元数据占用的块
+    pub total_blocks : usize,
+    pub free_blocks : usize,
+    /// 非特权用户可用的块，没有保留块的格式与 free_blocks 相同
+    pub available_blocks : usize,
+    /// 可容纳的文件数，没有固定上限的格式给出估计值
+    pub total_files : usize,
+    pub free_files : usize,
+    /// 文件名的最大字节数
+    pub name_max : usize,
+}
+
+/// ## 文件系统统计
+/// 对应 statfs，供 df 等工具使用
+pub struct StatFs {
+    pub block_size : usize,
+    pub total_blocks : usize,
+    pub free_blocks : usize,
+    pub available_blocks : usize,
+    pub total_files : usize,
+    pub free_files : usize,
+    pub name_max : usize,
+    pub label : String,
+    pub uuid : String,
+    pub read_only : bool,
+}
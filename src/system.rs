@@ -1,54 +1,110 @@
-use core::cmp::min;
+use core::{cmp::{max, min}, sync::atomic::{AtomicBool, AtomicUsize, Ordering}};
 
 use alloc::{collections::BTreeMap, sync::Arc};
 use alloc::prelude::v1::*;
-use device_buffer::CacheBuffer;
-use crate::{DirectoryItem, FileFlag, Leaf, SystemOp, directory::Directory, file::{File, FileState}, file_id::IdManager, node::Node, require::Format};
+use tisu_sync::ContentMutex;
+use crate::{Access, Cache, DirectoryDiff, DirectoryItem, EventMask, ExtentMap, Glob, Identity, Leaf, LeafType, ListOption, LockManager, LockType, MountOption, MountSource, NodeCache, OpenFile, OpenFlag, Permission, ShareMode, StatFs, SystemOp, SystemType, Walk, WalkOption, WatchEvent, WatchManager, WatchTarget, Wait, WriteMode, directory::Directory, file::{File, FileError, FileState}, file_id::IdManager, node::{Node, NodeError, NodeRef}, require::Format};
+
+pub type FileRef = Arc<ContentMutex<File>>;
+
+/// 文件表，path_to_id 与 files 一同加锁
+pub struct FileTable {
+    pub files : BTreeMap<usize, FileRef>,
+    pub path_to_id : BTreeMap<String, usize>,
+}
 
 /// ## 文件系统抽象
 /// 将磁盘中的文件系统抽象为一个 System，各种格式都转换成此结构
 /// 同时为文件的读写提供同步保证
 /// 文件操作以文件的标志为基础进行读写，使用前先获取标志
 /// 文件系统所有磁盘操作以块为基本单位
+/// 内部加锁，所有操作只需要 &self。需要同时持有多个锁时按照
+/// 文件表 -> 单个文件 -> 目录节点 -> 缓冲区 -> 脏块表 的顺序获取，监视表总在最后
 pub struct FileSystem {
-    pub id_mgr : &'static mut IdManager,
-    pub files : BTreeMap<usize, File>,
-    pub path_to_id : BTreeMap<String, usize>,
-    pub cache_buffer : &'static mut dyn CacheBuffer,
+    pub id_mgr : &'static IdManager,
+    pub files : ContentMutex<FileTable>,
+    pub cache_buffer : ContentMutex<&'static mut dyn Cache>,
     pub format : Arc<dyn Format>,
     pub total_size : usize,
     pub block_size : usize,
     pub block_start : usize,
     pub device_id : usize,
-    pub root : Node,
+    pub stype : SystemType,
+    pub label : ContentMutex<String>,
+    pub uuid : String,
+    pub version : usize,
+    pub root : NodeRef,
+    /// 目录缓存的占用与固定情况
+    pub nodes : NodeCache,
+    pub option : MountOption,
+    pub locks : LockManager,
+    pub watches : WatchManager,
+    /// 写回模式下被写过的块及其第一次变脏的时间
+    dirty : ContentMutex<BTreeMap<usize, usize>>,
+    /// 最近一次 writeback 给出的时间
+    clock : AtomicUsize,
+    /// 初始值来自挂载选项，remount 时改变
+    read_only : AtomicBool,
+    /// unmount 成功后置位，之后的操作都返回 IoError::Unmounted
+    unmounted : AtomicBool,
 }
 
+/// 所有操作只需要 &self，FileSystem 必须能在多个核之间共享
+fn assert_sync<T : Sync>() {}
+
+const _ : fn() = || assert_sync::<FileSystem>();
+
 impl FileSystem {
     pub fn new(
-        cache_buffer:&'static mut dyn CacheBuffer,
+        cache_buffer:&'static mut dyn Cache,
         format : Arc<dyn Format>,
-        id_mgr : &'static mut IdManager,
+        id_mgr : &'static IdManager,
+        waiter : &'static dyn Wait,
         device_id : usize,
+        option : MountOption,
     )->Self {
         let info = format.parse_super_block();
+        let read_only = option.read_only;
+        let budget = option.node_budget;
         let root = Node::new(String::from("root"), String::from("/"),
                 info.root_directory_block_idx,
             format.parse_node(info.root_directory_block_idx).unwrap());
         Self {
             id_mgr,
-            files: BTreeMap::new(),
-            path_to_id: BTreeMap::new(),
-            cache_buffer,
+            files: ContentMutex::new(FileTable {
+                files: BTreeMap::new(),
+                path_to_id: BTreeMap::new(),
+            }),
+            cache_buffer : ContentMutex::new(cache_buffer),
             format,
             total_size: info.total_size,
             block_size: info.block_size,
             device_id,
+            stype : info.stype,
+            label : ContentMutex::new(info.label),
+            uuid : info.uuid,
+            version : info.version,
             block_start : info.block_start_addr,
-            root,
+            root : Arc::new(ContentMutex::new(root)),
+            nodes : NodeCache::new(budget),
+            option,
+            locks : LockManager::new(waiter),
+            watches : WatchManager::new(waiter),
+            dirty : ContentMutex::new(BTreeMap::new()),
+            clock : AtomicUsize::new(0),
+            read_only : AtomicBool::new(read_only),
+            unmounted : AtomicBool::new(false),
         }
     }
 
-    fn format_path(&self, path :&String, dir : bool)->String {
+    /// 按 LABEL= 或 UUID= 在候选卷中找到匹配的一个，由调用者以 new 挂载
+    /// 先选定卷再交出缓冲区，没有匹配时调用者仍可换一个 spec 重试
+    pub fn select(spec : &str, formats : &[Arc<dyn Format>])->Option<Arc<dyn Format>> {
+        MountSource::parse(spec)?.find(formats)
+    }
+
+    /// 去掉首尾的空白与 '/'，dir 为真且不是根目录时以 '/' 结尾
+    pub fn format_path(&self, path :&String, dir : bool)->String {
         let rt = path.trim().to_string();
         let mut rt = rt.trim_matches('/').to_string();
         if rt.len() > 0 && dir{
@@ -57,19 +113,259 @@ impl FileSystem {
         rt
     }
 
-    fn generate_directory(&mut self, node : Node)->Result<Directory, ()> {
+    /// 沿路径取得目录节点，之后按预算收起目录缓存
+    fn walk_node(&self, path : &str)->Result<NodeRef, IoError> {
+        let node = Node::walk(&self.root, path, self.format.clone(), &self.nodes)
+            .map_err(IoError::from);
+        self.shrink();
+        node
+    }
+
+    fn search_leaf(&self, path : String)->Result<Leaf, IoError> {
+        let leaf = Node::search_leaf(&self.root, path, self.format.clone(), &self.nodes)
+            .map_err(IoError::from);
+        self.shrink();
+        leaf
+    }
+
+    /// 目录缓存超过预算时收起最久未使用的节点，调用者不能持有目录节点的锁
+    fn shrink(&self) {
+        for path in self.nodes.victims() {
+            let collapsed = match Node::find(&self.root, &path[..]) {
+                Some(node) => {
+                    let mut node = node.lock();
+                    node.collapse()
+                }
+                None => true,
+            };
+            if collapsed {
+                self.nodes.remove(&path[..]);
+            }
+        }
+    }
+
+    /// 取得目录节点的副本，需要目录的读权限与路径上的执行权限
+    pub fn list(&self, path : String, identity : &Identity)->Result<Node, IoError> {
+        self.check_mounted()?;
+        let path = self.format_path(&path, true);
+        self.check_search(&path, identity)?;
+        if !self.directory_permission(&path)?.allow(identity, Access::Read) {
+            return Err(IoError::PermissionDenied);
+        }
+        let node = Node::search_node(&self.root, path, self.format.clone(), &self.nodes);
+        self.shrink();
+        Ok(node?)
+    }
+
+    /// 遍历 path 下的目录树，见 Walk
+    pub fn walk(&self, path : String, option : WalkOption, identity : &Identity)->Result<Walk, IoError> {
+        self.check_mounted()?;
+        Walk::new(self, path, option, identity)
+    }
+
+    fn case_insensitive(&self)->bool {
+        self.option.case_insensitive.unwrap_or(self.stype == SystemType::FAT32)
+    }
+
+    /// 所在目录的起始块号，打开文件时取得一次，之后产生事件时直接使用
+    fn parent_idx(&self, path : &String)->Result<usize, IoError> {
+        let (parent, _) = Self::split_path(path);
+        let node = self.walk_node(&parent[..])?;
+        let idx = node.lock().block_idx;
+        Ok(idx)
+    }
+
+    fn permission_of(&self, leaf : &Leaf)->Permission {
+        leaf.permission.unwrap_or(self.option.default_permission())
+    }
+
+    /// 检查路径上每一级目录的执行权限，最后一项本身不检查，整条路径只走一遍
+    fn check_search(&self, path : &String, identity : &Identity)->Result<(), IoError> {
+        if !self.option.default_permission().allow(identity, Access::Execute) {
+            return Err(IoError::PermissionDenied);
+        }
+        let (parent, _) = Self::split_path(&path.trim_end_matches('/').to_string());
+        let node = Node::walk_checked(&self.root, &parent[..], self.format.clone(), &self.nodes,
+            |leaf| self.permission_of(leaf).allow(identity, Access::Execute));
+        self.shrink();
+        node.map(|_| ()).map_err(IoError::from)
+    }
+
+    /// 目录的权限，path 已经过 format_path 处理，根目录使用默认权限
+    fn directory_permission(&self, path : &String)->Result<Permission, IoError> {
+        if path.len() == 0 {
+            Ok(self.option.default_permission())
+        }
+        else {
+            let leaf = self.search_leaf(path.trim_end_matches('/').to_string())?;
+            if !leaf.is_directory() {
+                return Err(IoError::NotDirectory);
+            }
+            Ok(self.permission_of(&leaf))
+        }
+    }
+
+    /// 拆分为父目录与名字，父目录以 '/' 结尾
+    fn split_path(path : &String)->(String, String) {
+        match path.rsplit_once('/') {
+            Some((parent, name)) => (parent.to_string() + "/", name.to_string()),
+            None => (String::new(), path.clone()),
+        }
+    }
+
+    /// 在父目录中创建项，需要父目录的写权限
+    /// 持有父目录的锁再次检查是否存在，保证 EXCLUSIVE 的原子性
+    fn create_leaf(&self, path : &String, ltype : LeafType, identity : &Identity,
+            exclusive : bool)->Result<Leaf, IoError> {
+        if self.is_read_only() {
+            return Err(IoError::ReadOnly);
+        }
+        let (parent, name) = Self::split_path(path);
+        if !self.directory_permission(&parent)?.allow(identity, Access::Write) {
+            return Err(IoError::PermissionDenied);
+        }
+        let node = self.walk_node(&parent[..])?;
+        let mut node = node.lock();
+        let (leaf, cost) = node.lookup(&name[..], self.format.clone())?;
+        self.nodes.charge(&parent[..], cost);
+        if let Some(leaf) = leaf {
+            if exclusive {
+                return Err(IoError::Exists);
+            }
+            return Ok(leaf);
+        }
+        let leaf = self.format.create(node.block_idx, &name[..], ltype)
+            .map_err(|_| IoError::FormatErr)?;
+        node.insert(leaf.clone());
+        self.watches.emit(EventMask::CREATE, &path[..], node.block_idx, 0);
+        Ok(leaf)
+    }
+
+    /// 查找要打开的项，不存在时按 flag 创建
+    fn lookup_leaf(&self, path : &String, flag : OpenFlag, identity : &Identity)->Result<Leaf, IoError> {
+        let exclusive = flag.contains(OpenFlag::CREATE | OpenFlag::EXCLUSIVE);
+        match self.search_leaf(path.clone()) {
+            Ok(_) if exclusive => Err(IoError::Exists),
+            Ok(leaf) => Ok(leaf),
+            Err(IoError::NotFound) if flag.contains(OpenFlag::CREATE) && !flag.contains(OpenFlag::DIRECTORY) =>
+                self.create_leaf(path, LeafType::File, identity, exclusive),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// 改变文件大小，同时更新目录缓存与文件记录，调用者持有文件的锁
+    fn resize_file(&self, file : &mut File, size : usize)->Result<(), IoError> {
+        let (parent, name) = Self::split_path(&file.path);
+        let node = self.walk_node(&parent[..])?;
+        let mut node = node.lock();
+        let (leaf, cost) = node.lookup(&name[..], self.format.clone())?;
+        self.nodes.charge(&parent[..], cost);
+        let leaf = leaf.filter(|l| l.is_file()).ok_or(IoError::NotFound)?;
+        let leaf = self.format.resize(node.block_idx, &leaf, size)
+            .map_err(|_| IoError::FormatErr)?;
+        file.size = leaf.size;
+        file.start_idx = leaf.block_idx;
+        file.extents = None;
+        node.update(leaf);
+        Ok(())
+    }
+
+    /// 取得文件的块映射，没有缓存时从格式中解析，调用者持有文件的锁
+    fn extent_map(&self, file : &mut File)->Result<Arc<ExtentMap>, IoError> {
+        if file.extents.is_none() {
+            let extents = self.format.get_extents(file.start_idx)
+                .map_err(|_| IoError::FormatErr)?;
+            file.extents = Some(Arc::new(ExtentMap::new(extents)));
+        }
+        Ok(file.extents.clone().unwrap())
+    }
+
+    /// 把读取结束位置 ed 之后 window 个块读入缓冲区，已预读的块跳过
+    fn read_ahead(&self, cache : &mut dyn Cache, open : &OpenFile, map : &ExtentMap,
+            size : usize, ed : usize, window : usize) {
+        let bs = self.block_size;
+        let st = (ed + bs - 1) / bs;
+        let from = max(st, open.ahead());
+        let to = min(min(st + window, map.blocks()), (size + bs - 1) / bs);
+        if from >= to {
+            return;
+        }
+        let mut idx = from;
+        while idx < to {
+            let (block, run) = map.map(idx).unwrap();
+            let run = min(run, to - idx);
+            cache.prefetch(self.device_id, self.block_start + block * bs, run * bs);
+            idx += run;
+        }
+        open.set_ahead(to);
+    }
+
+    /// 记录写回模式下变脏的块，已经脏了的块保留原来的时间
+    fn mark_dirty(&self, block : usize, count : usize) {
+        let now = self.clock.load(Ordering::SeqCst);
+        let mut dirty = self.dirty.lock();
+        for b in block..block + count {
+            dirty.entry(b).or_insert(now);
+        }
+    }
+
+    /// 写回区间内的块，并从脏块表中移除
+    fn flush_extent(&self, start : usize, len : usize) {
+        self.cache_buffer.lock().flush(self.device_id,
+            self.block_start + start * self.block_size, len * self.block_size);
+        let mut dirty = self.dirty.lock();
+        for b in start..start + len {
+            dirty.remove(&b);
+        }
+    }
+
+    fn is_read_only(&self)->bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    fn is_unmounted(&self)->bool {
+        self.unmounted.load(Ordering::SeqCst)
+    }
+
+    fn check_mounted(&self)->Result<(), IoError> {
+        if self.is_unmounted() { Err(IoError::Unmounted) }
+        else { Ok(()) }
+    }
+
+    /// 文件关闭且没有所有者后移出文件表，并回收句柄
+    fn release_file(&self, id : usize) {
+        let mut table = self.files.lock();
+        let unused = match table.files.get(&id) {
+            Some(file) => {
+                let file = file.lock();
+                file.is_close() && file.state.owner.len() == 0
+            }
+            None => false,
+        };
+        if unused {
+            let file = table.files.remove(&id).unwrap();
+            let path = file.lock().path.clone();
+            self.nodes.unpin(&Self::split_path(&path).0[..]);
+            if table.path_to_id.get(&path) == Some(&id) {
+                table.path_to_id.remove(&path);
+            }
+            self.id_mgr.release(id);
+        }
+    }
+
+    fn file_ref(&self, id : usize)->Option<FileRef> {
+        self.files.lock().files.get(&id).cloned()
+    }
+
+    fn check_open(&self, file : &OpenFile)->Result<(), IoError> {
+        if self.files.lock().files.contains_key(&file.file_id) { Ok(()) }
+        else { Err(IoError::FileClosed) }
+    }
+
+    fn generate_directory(&self, node : Node)->Result<Directory, ()> {
         let mut item = Vec::new();
-        for file in node.file.iter() {
-            item.push(DirectoryItem {
-                name: file.name.clone(),
-                itype: crate::DirItemType::File,
-            });
-        }
-        for dir in node.directory.iter() {
-            item.push(DirectoryItem {
-                name : dir.name.clone(),
-                itype : crate::DirItemType::Directory
-            })
+        for leaf in node.file.iter().chain(node.directory.iter()) {
+            item.push(DirectoryItem::new(leaf.name.clone(), leaf, self.permission_of(leaf)));
         }
         Ok(Directory {
             name: node.name.clone(),
@@ -80,97 +376,293 @@ impl FileSystem {
         })
     }
 
-    fn generate_file(&mut self, leaf : Leaf, path : String)->Result<&mut File, &str> {
-        if leaf.is_file() {
-            if let Some(id) = self.path_to_id.get(&path) {
-                let file = self.files.get_mut(id).unwrap();
-                Ok(file)
-            }
-            else {
-                let file = File {
-                    id : self.id_mgr.get(),
-                    device_id: self.device_id,
-                    start_idx : leaf.block_idx,
-                    name: leaf.name.clone(),
-                    state: FileState::new(),
-                    path : path.clone(),
-                    size: leaf.size,
-                };
-                let id = file.id;
-                self.path_to_id.insert(path, file.id);
-                self.files.insert(file.id, file);
-                Ok(self.files.get_mut(&id).unwrap())
-            }
+    /// 检查类型与权限并登记打开状态，返回是否需要截断
+    fn open_file(&self, file : &mut File, task_id : usize, flag : OpenFlag, share : ShareMode,
+            identity : &Identity)->Result<(OpenFile, bool), IoError> {
+        let is_dir = file.ltype == LeafType::Directory;
+        if flag.contains(OpenFlag::DIRECTORY) && !is_dir {
+            return Err(IoError::NotDirectory);
+        }
+        if !flag.contains(OpenFlag::DIRECTORY) && is_dir {
+            return Err(IoError::IsDirectory);
+        }
+        let open = OpenFile::new(self.device_id, file.id, task_id, flag);
+        if is_dir && open.writable() {
+            return Err(IoError::IsDirectory);
+        }
+        if open.writable() && self.is_read_only() {
+            return Err(IoError::ReadOnly);
+        }
+        if (open.readable() && !file.permission.allow(identity, Access::Read)) ||
+            (open.writable() && !file.permission.allow(identity, Access::Write)) {
+            return Err(IoError::PermissionDenied);
+        }
+        let truncate = flag.contains(OpenFlag::TRUNCATE) && open.writable() && file.size > 0;
+        file.open(flag.access(), share)?;
+        file.own(task_id);
+        Ok((open, truncate))
+    }
+
+    fn file_info(&self, id : usize, leaf : Leaf, path : String, dir_idx : usize)->File {
+        File {
+            id,
+            device_id: self.device_id,
+            dir_idx,
+            start_idx : leaf.block_idx,
+            name: leaf.name.clone(),
+            state: FileState::new(),
+            path,
+            size: leaf.size,
+            permission : self.permission_of(&leaf),
+            ltype: leaf.ltype,
+            extents: None,
+            deleted: false,
+        }
+    }
+
+    /// 调用者持有文件表的锁
+    fn generate_file(&self, table : &mut FileTable, leaf : Leaf, path : String, dir_idx : usize)
+            ->FileRef {
+        if let Some(id) = table.path_to_id.get(&path) {
+            table.files.get(id).unwrap().clone()
         }
         else {
-            Err("Isn't a file")
+            let file = self.file_info(self.id_mgr.get(), leaf, path.clone(), dir_idx);
+            let id = file.id;
+            let file = Arc::new(ContentMutex::new(file));
+            self.nodes.pin(&Self::split_path(&path).0[..]);
+            table.path_to_id.insert(path, id);
+            table.files.insert(id, file.clone());
+            file
         }
     }
 }
 
 impl SystemOp for FileSystem {
-    fn file(&mut self, id : usize)->Option<&mut File> {
-        self.files.get_mut(&id)
+    fn file(&self, id : usize)->Option<File> {
+        if self.is_unmounted() {
+            return None;
+        }
+        let file = self.file_ref(id)?;
+        let rt = file.lock().clone();
+        Some(rt)
     }
 
-    fn open(&mut self, path : String, flag : FileFlag)->Result<&mut File, ()> {
+    /// 登记打开状态时持有文件表的锁，避免文件在此期间被移出文件表
+    fn open(&self, task_id : usize, path : String, flag : OpenFlag, share : ShareMode,
+            identity : &Identity)->Result<OpenFile, IoError> {
+        self.check_mounted()?;
+        let flag = OpenFlag::from(flag.val()).ok_or(IoError::InvalidFlag)?;
+        let exclusive = flag.contains(OpenFlag::CREATE | OpenFlag::EXCLUSIVE);
         let path = self.format_path(&path, false);
-        if let Some(id) = self.path_to_id.get(&path) {
-            let file = self.files.get_mut(id).unwrap();
-            file.open(flag).unwrap();
-            Ok(file)
-        }
-        else {
-            let leaf = self.root.search_leaf(path.clone(), self.format.clone()).unwrap();
-            let file = self.generate_file(leaf, path).unwrap();
-            file.open(flag).unwrap();
-            Ok(file)
+        self.check_search(&path, identity)?;
+        let mut leaf = None;
+        loop {
+            let mut table = self.files.lock();
+            self.check_mounted()?;
+            let cached = table.path_to_id.get(&path).cloned();
+            let file = match (cached, leaf.take()) {
+                (Some(_), None) if exclusive => return Err(IoError::Exists),
+                (Some(id), _) => table.files.get(&id).unwrap().clone(),
+                (None, Some((leaf, dir_idx))) =>
+                    self.generate_file(&mut table, leaf, path.clone(), dir_idx),
+                (None, None) => {
+                    drop(table);
+                    let found = self.lookup_leaf(&path, flag, identity)?;
+                    leaf = Some((found, self.parent_idx(&path)?));
+                    continue;
+                }
+            };
+            let id = file.lock().id;
+            let rt = self.open_file(&mut file.lock(), task_id, flag, share, identity);
+            drop(table);
+            let (open, truncate) = match rt {
+                Ok(rt) => rt,
+                Err(err) => {
+                    self.release_file(id);
+                    return Err(err);
+                }
+            };
+            if truncate {
+                let mut f = file.lock();
+                if let Err(err) = self.resize_file(&mut f, 0) {
+                    f.close(flag.access());
+                    f.disown(task_id);
+                    drop(f);
+                    self.release_file(id);
+                    return Err(err);
+                }
+                self.watches.emit(EventMask::MODIFY, &path[..], f.dir_idx, 0);
+            }
+            return Ok(open);
         }
     }
 
-    fn close(&mut self, id : usize) {
-        if let Some(file) = self.files.get_mut(&id) {
-            file.close();
+    fn close(&self, file : OpenFile) {
+        if self.is_unmounted() {
+            return;
         }
+        let id = file.file_id;
+        if let Some(f) = self.file_ref(id) {
+            let mut f = f.lock();
+            f.close(file.flag.access());
+            f.disown(file.task_id);
+        }
+        self.locks.release(id, file.task_id);
+        self.release_file(id);
     }
 
-    fn enter(&mut self, path : String)->Result<Directory, ()> {
-        let path = self.format_path(&path, true);
-        let node = self.root.search_node(path, self.format.clone()).unwrap();
-        self.generate_directory(node)
+    fn enter(&self, path : String, identity : &Identity)->Result<Directory, IoError> {
+        self.check_mounted()?;
+        let node = self.list(path, identity)?;
+        self.generate_directory(node).map_err(|_| IoError::NotFound)
+    }
+
+    fn enter_with(&self, path : String, option : &ListOption, identity : &Identity)
+            ->Result<Directory, IoError> {
+        self.check_mounted()?;
+        let mut dir = self.enter(path, identity)?;
+        dir.arrange(option);
+        Ok(dir)
+    }
+
+    fn enter_glob(&self, path : String, pattern : &str, identity : &Identity)->Result<Directory, IoError> {
+        self.check_mounted()?;
+        let glob = Glob::new(pattern, self.case_insensitive());
+        let mut dir = self.enter(path.clone(), identity)?;
+        if !glob.is_recursive() {
+            dir.item.retain(|item| glob.matches(&item.name[..]));
+            return Ok(dir);
+        }
+        let mut option = WalkOption::new();
+        option.max_depth = glob.max_depth();
+        let base = self.format_path(&path, false);
+        let skip = if base.len() == 0 { 0 } else { base.len() + 1 };
+        dir.item.clear();
+        for entry in self.walk(path, option, identity)? {
+            if let Ok(entry) = entry {
+                let name = &entry.path[skip..];
+                if glob.matches(name) {
+                    let permission = self.permission_of(&entry.leaf);
+                    dir.item.push(DirectoryItem::new(name.to_string(), &entry.leaf, permission));
+                }
+            }
+        }
+        Ok(dir)
     }
 
-    fn get_file(&mut self, path : String)->Result<File, IoError> {
+    /// 直接由目录项生成，与文件是否打开无关，不接触文件表
+    fn get_file(&self, path : String)->Result<DirectoryItem, IoError> {
+        self.check_mounted()?;
         let path = self.format_path(&path, false);
-        if let Some(id) = self.path_to_id.get(&path) {
-            let file = self.files.get_mut(id).unwrap();
-            Ok(file.clone())
+        let leaf = self.search_leaf(path)?;
+        if !leaf.is_file() {
+            return Err(IoError::IsDirectory);
         }
-        else {
-            let leaf = self.root.search_leaf(path.clone(), self.format.clone()).unwrap();
-            let file = self.generate_file(leaf, path).unwrap();
-            Ok(file.clone())
+        Ok(DirectoryItem::new(leaf.name.clone(), &leaf, self.permission_of(&leaf)))
+    }
+
+    /// 全程持有文件表的锁，期间不会有新的打开
+    fn rename(&self, old : String, new : String, identity : &Identity)->Result<(), IoError> {
+        self.check_mounted()?;
+        if self.is_read_only() {
+            return Err(IoError::ReadOnly);
+        }
+        let old = self.format_path(&old, false);
+        let new = self.format_path(&new, false);
+        if old.len() == 0 || new.len() == 0 {
+            return Err(IoError::PermissionDenied);
+        }
+        let prefix = old.clone() + "/";
+        if new.starts_with(&prefix[..]) {
+            return Err(IoError::InvalidPath);
+        }
+        self.check_search(&old, identity)?;
+        self.check_search(&new, identity)?;
+        let (old_parent, old_name) = Self::split_path(&old);
+        let (new_parent, new_name) = Self::split_path(&new);
+        for parent in [&old_parent, &new_parent].iter() {
+            if !self.directory_permission(parent)?.allow(identity, Access::Write) {
+                return Err(IoError::PermissionDenied);
+            }
+        }
+        let mut table = self.files.lock();
+        let leaf = self.search_leaf(old.clone())?;
+        if old == new {
+            return Ok(());
+        }
+        if self.search_leaf(new.clone()).is_ok() {
+            return Err(IoError::Exists);
+        }
+        if leaf.is_directory() && table.path_to_id.keys().any(|p| p.starts_with(&prefix[..])) {
+            return Err(IoError::Busy);
         }
+        let src = self.walk_node(&old_parent[..])?;
+        let dst = self.walk_node(&new_parent[..])?;
+        let src_idx = src.lock().block_idx;
+        let dst_idx = dst.lock().block_idx;
+        let moved = self.format.rename(src_idx, &leaf, dst_idx, &new_name[..])
+            .map_err(|_| IoError::FormatErr)?;
+        src.lock().remove(&old_name[..]);
+        dst.lock().insert(moved.clone());
+        let cookie = self.watches.cookie();
+        self.watches.emit(EventMask::MOVED_FROM, &old[..], src_idx, cookie);
+        self.watches.emit(EventMask::MOVED_TO, &new[..], dst_idx, cookie);
+        if leaf.is_directory() {
+            self.nodes.forget(&prefix[..]);
+        }
+        if let Some(id) = table.path_to_id.remove(&old) {
+            table.path_to_id.insert(new.clone(), id);
+            let file = table.files.get(&id).unwrap().clone();
+            let mut file = file.lock();
+            file.path = new;
+            file.name = new_name;
+            file.start_idx = moved.block_idx;
+            file.dir_idx = dst_idx;
+            self.nodes.unpin(&old_parent[..]);
+            self.nodes.pin(&new_parent[..]);
+        }
+        Ok(())
     }
 
-    fn read(&mut self, id : usize, data : &mut [u8])->IoResult {
-        if let Some(file) = self.files.get_mut(&id) {
-            if file.readable() {
-                let leaf = self.root.search_leaf(file.path.clone(), self.format.clone()).unwrap();
-                let block_chain =
-                    self.format.get_block_chain(leaf.block_idx).unwrap();
-                let mut len = 0;
-                for (idx, addr) in block_chain.iter().enumerate() {
-                    let st = idx * self.block_size;
-                    if st >= data.len() {
-                        break;
+    /// 顺序读时按窗口预读后续的块，窗口随顺序读增长，seek 或随机读时清零
+    fn read(&self, open : &OpenFile, data : &mut [u8])->IoResult {
+        self.check_mounted()?;
+        if let Some(file) = self.file_ref(open.file_id) {
+            if open.flag.contains(OpenFlag::DIRECTORY) {
+                return Err(IoError::IsDirectory);
+            }
+            if open.readable() {
+                let st = open.offset();
+                let (size, map) = {
+                    let mut file = file.lock();
+                    if file.deleted {
+                        return Err(IoError::NotFound);
                     }
-                    let ed = min((idx + 1) * self.block_size, data.len());
-                    let data = &mut data[st..ed];
-                    len += ed - st;
-                    self.cache_buffer.read(self.device_id, data,
-                        self.block_start + *addr * self.block_size);
+                    if st >= file.size {
+                        return Ok(0);
+                    }
+                    (file.size, self.extent_map(&mut file)?)
+                };
+                let total = min(data.len(), size - st);
+                let mut cache = self.cache_buffer.lock();
+                let mut len = 0;
+                while len < total {
+                    let pos = st + len;
+                    let (block, run) = match map.map(pos / self.block_size) {
+                        Some(rt) => rt,
+                        None => break,
+                    };
+                    let ed = min(len + run * self.block_size - pos % self.block_size, total);
+                    cache.read(self.device_id, &mut data[len..ed],
+                        self.block_start + block * self.block_size + pos % self.block_size);
+                    len = ed;
                 }
+                let window = open.sequential(st, st + len, self.option.read_ahead);
+                if window > 0 {
+                    self.read_ahead(&mut **cache, open, &map, size, st + len, window);
+                }
+                open.advance(len);
                 Ok(len)
             }
             else { Err(IoError::ReadFromWrite) }
@@ -178,50 +670,392 @@ impl SystemOp for FileSystem {
         else { Err(IoError::FileClosed) }
     }
 
-    fn write(&mut self, id : usize, data : &[u8])->IoResult {
-        if let Some(file) = self.files.get_mut(&id) {
-            if file.writable() {
-                let leaf = self.root.search_leaf(file.path.clone(), self.format.clone()).unwrap();
-                let block_chain =
-                    self.format.get_block_chain(leaf.block_idx).unwrap();
-                let mut len = 0;
-                for (idx, addr) in block_chain.iter().enumerate() {
-                    let st = idx * self.block_size;
-                    if st >= data.len() {
-                        break;
-                    }
-                    let ed = min((idx + 1) * self.block_size, data.len());
-                    let data = &data[st..ed];
-                    len += ed - st;
-                    self.cache_buffer.write(self.device_id, data,
-                        self.block_start + *addr * self.block_size);
+    /// 写入超过文件末尾时先扩展文件，写入期间持有文件的锁
+    /// 每个连续区间只向缓冲区发起一次传输
+    fn write(&self, open : &OpenFile, data : &[u8])->IoResult {
+        self.check_mounted()?;
+        let file = match self.file_ref(open.file_id) {
+            Some(file) => file,
+            None => return Err(IoError::FileClosed),
+        };
+        if !open.writable() {
+            return Err(IoError::WriteToReadOnly);
+        }
+        if self.is_read_only() {
+            return Err(IoError::ReadOnly);
+        }
+        if data.len() == 0 {
+            return Ok(0);
+        }
+        let mut file = file.lock();
+        if file.deleted {
+            return Err(IoError::NotFound);
+        }
+        if open.flag.contains(OpenFlag::APPEND) {
+            open.seek(file.size);
+        }
+        let st = open.offset();
+        let end = st.checked_add(data.len()).ok_or(IoError::TooLarge)?;
+        if end > file.size {
+            self.resize_file(&mut file, end)?;
+        }
+        let map = self.extent_map(&mut file)?;
+        let mut cache = self.cache_buffer.lock();
+        let mut written = Vec::new();
+        let mut len = 0;
+        while len < data.len() {
+            let pos = st + len;
+            let (block, run) = match map.map(pos / self.block_size) {
+                Some(rt) => rt,
+                None => break,
+            };
+            let ed = min(len + run * self.block_size - pos % self.block_size, data.len());
+            let addr = self.block_start + block * self.block_size + pos % self.block_size;
+            cache.write(self.device_id, &data[len..ed], addr);
+            match self.option.write_mode {
+                WriteMode::WriteThrough => cache.flush(self.device_id, addr, ed - len),
+                WriteMode::WriteBack { .. } => {
+                    let count = (pos % self.block_size + ed - len + self.block_size - 1) / self.block_size;
+                    written.push((block, count));
                 }
-                Ok(len)
             }
-            else { Err(IoError::WriteToReadOnly) }
+            len = ed;
         }
-        else { Err(IoError::FileClosed) }
+        drop(cache);
+        for (block, count) in written {
+            self.mark_dirty(block, count);
+        }
+        open.advance(len);
+        self.watches.emit(EventMask::MODIFY, &file.path[..], file.dir_idx, 0);
+        Ok(len)
     }
 
-    fn refresh(&mut self, dir : &Directory) {
-        self.root.refresh(dir.path.clone(), self.format.clone()).unwrap();
+    fn fsync(&self, open : &OpenFile)->Result<(), IoError> {
+        self.check_mounted()?;
+        self.fdatasync(open)?;
+        if self.is_read_only() {
+            return Ok(());
+        }
+        let file = self.file_ref(open.file_id).ok_or(IoError::FileClosed)?;
+        let path = file.lock().path.clone();
+        let (parent, _) = Self::split_path(&path);
+        let node = self.walk_node(&parent[..])?;
+        let block_idx = node.lock().block_idx;
+        for e in self.format.get_extents(block_idx).map_err(|_| IoError::FormatErr)? {
+            self.flush_extent(e.start, e.len);
+        }
+        self.format.sync().map_err(|_| IoError::FormatErr)
+    }
+
+    fn fdatasync(&self, open : &OpenFile)->Result<(), IoError> {
+        self.check_mounted()?;
+        let file = match self.file_ref(open.file_id) {
+            Some(file) => file,
+            None => return Err(IoError::FileClosed),
+        };
+        let mut file = file.lock();
+        if file.size == 0 {
+            return Ok(());
+        }
+        let map = self.extent_map(&mut file)?;
+        for e in map.extents() {
+            self.flush_extent(e.start, e.len);
+        }
+        Ok(())
+    }
+
+    fn sync(&self)->Result<(), IoError> {
+        self.check_mounted()?;
+        if self.is_read_only() {
+            return Ok(());
+        }
+        self.format.sync().map_err(|_| IoError::FormatErr)?;
+        self.cache_buffer.lock().flush_all(self.device_id);
+        self.dirty.lock().clear();
+        Ok(())
+    }
+
+    /// 全程持有文件表的锁，卸载期间不会有新的打开
+    fn unmount(&self, force : bool)->Result<(), IoError> {
+        let mut table = self.files.lock();
+        self.check_mounted()?;
+        let busy = table.files.values().any(|file| !file.lock().is_close());
+        if busy && !force {
+            return Err(IoError::Busy);
+        }
+        if !self.is_read_only() {
+            let mut cache = self.cache_buffer.lock();
+            cache.flush_all(self.device_id);
+            self.format.unmount().map_err(|_| IoError::FormatErr)?;
+            cache.flush_all(self.device_id);
+        }
+        self.dirty.lock().clear();
+        for (id, file) in table.files.iter() {
+            let (owner, path) = {
+                let file = file.lock();
+                (file.state.owner.clone(), file.path.clone())
+            };
+            self.nodes.unpin(&Self::split_path(&path).0[..]);
+            for task_id in owner {
+                self.locks.release(*id, task_id);
+            }
+            self.id_mgr.release(*id);
+        }
+        table.files.clear();
+        table.path_to_id.clear();
+        self.unmounted.store(true, Ordering::SeqCst);
+        self.watches.clear();
+        Ok(())
+    }
+
+    /// 持有文件表的锁检查写者，期间不会有新的写方式打开
+    fn remount(&self, read_only : bool)->Result<(), IoError> {
+        self.check_mounted()?;
+        let table = self.files.lock();
+        if read_only && !self.is_read_only() {
+            if table.files.values().any(|file| file.lock().writable()) {
+                return Err(IoError::Busy);
+            }
+            let mut cache = self.cache_buffer.lock();
+            cache.flush_all(self.device_id);
+            self.format.sync().map_err(|_| IoError::FormatErr)?;
+            cache.flush_all(self.device_id);
+            drop(cache);
+            self.dirty.lock().clear();
+        }
+        self.read_only.store(read_only, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn read_only(&self)->bool {
+        self.is_read_only()
+    }
+
+    fn writeback(&self, now : usize) {
+        if self.is_unmounted() {
+            return;
+        }
+        self.clock.store(now, Ordering::SeqCst);
+        let max_age = match self.option.write_mode {
+            WriteMode::WriteBack { max_age } => max_age,
+            WriteMode::WriteThrough => return,
+        };
+        let mut expired = Vec::new();
+        for (block, time) in self.dirty.lock().iter() {
+            if now.saturating_sub(*time) >= max_age {
+                expired.push(*block);
+            }
+        }
+        let mut idx = 0;
+        while idx < expired.len() {
+            let mut len = 1;
+            while idx + len < expired.len() && expired[idx + len] == expired[idx] + len {
+                len += 1;
+            }
+            self.flush_extent(expired[idx], len);
+            idx += len;
+        }
+    }
+
+    /// 先刷新目录节点，释放后再按 文件表 -> 单个文件 的顺序更新文件记录
+    fn refresh(&self, dir : &Directory)->Result<DirectoryDiff, IoError> {
+        self.check_mounted()?;
+        let path = self.format_path(&dir.path, true);
+        let (diff, dir_idx) = Node::refresh(&self.root, path.clone(), self.format.clone(), &self.nodes)?;
+        for leaf in diff.added.iter() {
+            self.watches.emit(EventMask::CREATE, &(path.clone() + &leaf.name[..])[..], dir_idx, 0);
+        }
+        for leaf in diff.removed.iter() {
+            self.watches.emit(EventMask::DELETE, &(path.clone() + &leaf.name[..])[..], dir_idx, 0);
+        }
+        let mut table = self.files.lock();
+        for leaf in diff.removed.iter() {
+            let item = path.clone() + &leaf.name[..];
+            let prefix = item.clone() + "/";
+            let gone : Vec<String> = table.path_to_id.keys()
+                .filter(|p| **p == item || (leaf.is_directory() && p.starts_with(&prefix[..])))
+                .cloned()
+                .collect();
+            for p in gone {
+                let id = table.path_to_id.remove(&p).unwrap();
+                let mut file = table.files.get(&id).unwrap().lock();
+                file.deleted = true;
+                file.size = 0;
+                file.extents = None;
+            }
+        }
+        for (old, leaf) in diff.changed.iter() {
+            let item = path.clone() + &leaf.name[..];
+            let modified = old.size != leaf.size || old.block_idx != leaf.block_idx;
+            if let Some(id) = table.path_to_id.get(&item) {
+                let mut file = table.files.get(id).unwrap().lock();
+                file.permission = self.permission_of(leaf);
+                if modified {
+                    file.size = leaf.size;
+                    file.start_idx = leaf.block_idx;
+                    file.extents = None;
+                }
+            }
+            let mask = if modified { EventMask::MODIFY } else { EventMask::ATTRIBUTE };
+            self.watches.emit(mask, &item[..], dir_idx, 0);
+        }
+        Ok(diff)
     }
 
     fn total_size(&self)->usize {
         self.total_size
     }
 
+    fn statfs(&self)->Result<StatFs, IoError> {
+        self.check_mounted()?;
+        let info = self.format.statfs().map_err(|_| IoError::FormatErr)?;
+        Ok(StatFs {
+            block_size : self.block_size,
+            total_blocks : info.total_blocks,
+            free_blocks : info.free_blocks,
+            available_blocks : info.available_blocks,
+            total_files : info.total_files,
+            free_files : info.free_files,
+            name_max : info.name_max,
+            label : self.label.lock().clone(),
+            uuid : self.uuid.clone(),
+            read_only : self.is_read_only(),
+        })
+    }
+
     fn block_size(&self)->usize {
         self.block_size
     }
 
+    fn label(&self)->String {
+        self.label.lock().clone()
+    }
+
+    fn uuid(&self)->String {
+        self.uuid.clone()
+    }
+
+    fn version(&self)->usize {
+        self.version
+    }
+
+    fn set_label(&self, label : &str)->Result<(), IoError> {
+        self.check_mounted()?;
+        if self.is_read_only() {
+            return Err(IoError::ReadOnly);
+        }
+        let mut old = self.label.lock();
+        self.format.set_label(label).map_err(|_| IoError::FormatErr)?;
+        *old = label.to_string();
+        Ok(())
+    }
+
     fn contain(&self, id : usize)->bool {
-        self.files.contains_key(&id)
+        if self.is_unmounted() {
+            return false;
+        }
+        self.files.lock().files.contains_key(&id)
     }
 
     fn check(&self) ->usize {
         self.format.get_device()
     }
+
+    fn try_flock(&self, file : &OpenFile, ltype : LockType)->Result<(), IoError> {
+        self.check_mounted()?;
+        self.check_open(file)?;
+        if self.locks.try_flock(file.file_id, file.task_id, ltype) { Ok(()) }
+        else { Err(IoError::WouldBlock) }
+    }
+
+    fn flock(&self, file : &OpenFile, ltype : LockType)->Result<(), IoError> {
+        self.check_mounted()?;
+        self.check_open(file)?;
+        if self.locks.flock(file.file_id, file.task_id, ltype) { Ok(()) }
+        else { Err(IoError::Interrupted) }
+    }
+
+    fn funlock(&self, file : &OpenFile)->Result<(), IoError> {
+        self.check_mounted()?;
+        self.check_open(file)?;
+        self.locks.funlock(file.file_id, file.task_id);
+        Ok(())
+    }
+
+    fn try_lock_range(&self, file : &OpenFile, ltype : LockType, start : usize, len : usize)
+            ->Result<(), IoError> {
+        self.check_mounted()?;
+        self.check_open(file)?;
+        if self.locks.try_lock_range(file.file_id, file.task_id, ltype, start, len) { Ok(()) }
+        else { Err(IoError::WouldBlock) }
+    }
+
+    fn lock_range(&self, file : &OpenFile, ltype : LockType, start : usize, len : usize)
+            ->Result<(), IoError> {
+        self.check_mounted()?;
+        self.check_open(file)?;
+        if self.locks.lock_range(file.file_id, file.task_id, ltype, start, len) { Ok(()) }
+        else { Err(IoError::Interrupted) }
+    }
+
+    fn unlock_range(&self, file : &OpenFile, start : usize, len : usize)->Result<(), IoError> {
+        self.check_mounted()?;
+        self.check_open(file)?;
+        self.locks.unlock_range(file.file_id, file.task_id, start, len);
+        Ok(())
+    }
+
+    fn release_locks(&self, task_id : usize) {
+        self.locks.release_task(task_id);
+    }
+
+    fn add_watch(&self, task_id : usize, target : WatchTarget, mask : EventMask,
+            identity : &Identity)->Result<usize, IoError> {
+        self.check_mounted()?;
+        let path = match &target {
+            WatchTarget::Path(path) => self.format_path(path, false),
+            WatchTarget::Directory(idx) => {
+                let node = Node::locate(&self.root, *idx).ok_or(IoError::NotFound)?;
+                let path = node.lock().path.clone();
+                self.format_path(&path, false)
+            }
+        };
+        self.check_search(&path, identity)?;
+        let permission = if path.len() == 0 {
+            self.option.default_permission()
+        }
+        else {
+            self.permission_of(&self.search_leaf(path.clone())?)
+        };
+        if !permission.allow(identity, Access::Read) {
+            return Err(IoError::PermissionDenied);
+        }
+        let target = match target {
+            WatchTarget::Path(_) => WatchTarget::Path(path),
+            target => target,
+        };
+        Ok(self.watches.add(task_id, target, mask))
+    }
+
+    fn remove_watch(&self, wd : usize)->Result<(), IoError> {
+        self.check_mounted()?;
+        if self.watches.remove(wd) { Ok(()) }
+        else { Err(IoError::NotFound) }
+    }
+
+    fn read_events(&self, wd : usize)->Result<Vec<WatchEvent>, IoError> {
+        self.check_mounted()?;
+        self.watches.read(wd).ok_or(IoError::NotFound)
+    }
+
+    fn wait_events(&self, wd : usize)->Result<Vec<WatchEvent>, IoError> {
+        self.check_mounted()?;
+        self.watches.wait(wd)
+    }
+
+    fn release_watches(&self, task_id : usize) {
+        self.watches.release_task(task_id);
+    }
 }
 
 
@@ -232,4 +1066,47 @@ pub enum IoError {
     WriteToReadOnly,
     ReadFromWrite,
     FileClosed,
+    PermissionDenied,
+    NotFound,
+    Exists,
+    IsDirectory,
+    NotDirectory,
+    /// 格式不支持该操作或操作失败
+    FormatErr,
+    InvalidFlag,
+    /// 不能把目录移动到自身之下
+    InvalidPath,
+    /// 文件已被以不兼容的方式打开
+    Busy,
+    /// 锁被占用，非阻塞调用直接返回
+    WouldBlock,
+    /// 等待被信号等打断
+    Interrupted,
+    /// 文件系统以只读方式挂载
+    ReadOnly,
+    /// 文件系统已卸载
+    Unmounted,
+    /// 位置或大小超出可表示的范围
+    TooLarge,
+}
+
+impl From<FileError> for IoError {
+    fn from(err : FileError)->Self {
+        match err {
+            FileError::WriteToReadOnly => IoError::WriteToReadOnly,
+            FileError::ReadFromWrite => IoError::ReadFromWrite,
+            FileError::FlagErr(_) => IoError::InvalidFlag,
+            FileError::Busy => IoError::Busy,
+        }
+    }
+}
+
+impl From<NodeError> for IoError {
+    fn from(err : NodeError)->Self {
+        match err {
+            NodeError::NoFile(_) | NodeError::NoDirectory(_) => IoError::NotFound,
+            NodeError::Denied(_) => IoError::PermissionDenied,
+            NodeError::ExpendErr => IoError::FormatErr,
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,114 @@
+//! # 目录树遍历
+//! 从一个目录开始逐项产生其下所有的项，目录的内容经由目录缓存取得
+//! 目录在产生时才展开，无法进入的目录紧接着产生一个错误，之后继续遍历其余部分
+//! 现有格式都不产生符号链接，暂不支持跟随符号链接
+
+use alloc::{collections::VecDeque, prelude::v1::*};
+use crate::{FileSystem, Identity, IoError, Leaf};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WalkOrder {
+    /// 先序，目录之后紧跟其内容
+    DepthFirst,
+    /// 逐层
+    BreadthFirst,
+}
+
+pub struct WalkOption {
+    pub order : WalkOrder,
+    /// 起点的直接子项深度为 1，更深的项不产生
+    pub max_depth : usize,
+    /// 返回 false 的项不产生，目录也不进入
+    pub filter : Option<Box<dyn Fn(&WalkEntry)->bool>>,
+}
+
+impl WalkOption {
+    pub fn new()->Self {
+        Self {
+            order : WalkOrder::DepthFirst,
+            max_depth : usize::MAX,
+            filter : None,
+        }
+    }
+}
+
+pub struct WalkEntry {
+    /// 不以 '/' 开头
+    pub path : String,
+    pub leaf : Leaf,
+    pub depth : usize,
+}
+
+pub struct Walk<'a> {
+    system : &'a FileSystem,
+    option : WalkOption,
+    identity : Identity,
+    pending : VecDeque<WalkEntry>,
+    error : Option<IoError>,
+}
+
+impl<'a> Walk<'a> {
+    /// 起点本身不产生，起点无法进入时直接失败，max_depth 为 0 时不产生任何项
+    pub fn new(system : &'a FileSystem, path : String, option : WalkOption, identity : &Identity)
+            ->Result<Self, IoError> {
+        let mut walk = Self {
+            system,
+            option,
+            identity : identity.clone(),
+            pending : VecDeque::new(),
+            error : None,
+        };
+        let path = system.format_path(&path, false);
+        if walk.option.max_depth > 0 {
+            walk.expand(&path, 1)?;
+        }
+        else {
+            system.list(path, identity)?;
+        }
+        Ok(walk)
+    }
+
+    fn expand(&mut self, path : &String, depth : usize)->Result<(), IoError> {
+        let node = self.system.list(path.clone(), &self.identity)?;
+        let mut children = Vec::new();
+        for leaf in node.directory.iter().chain(node.file.iter()) {
+            let entry = WalkEntry {
+                path : if path.len() == 0 { leaf.name.clone() } else { path.clone() + "/" + &leaf.name[..] },
+                leaf : leaf.clone(),
+                depth,
+            };
+            if self.option.filter.as_ref().map_or(true, |f| f(&entry)) {
+                children.push(entry);
+            }
+        }
+        match self.option.order {
+            WalkOrder::DepthFirst => {
+                for entry in children.into_iter().rev() {
+                    self.pending.push_back(entry);
+                }
+            }
+            WalkOrder::BreadthFirst => self.pending.extend(children),
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = Result<WalkEntry, IoError>;
+
+    fn next(&mut self)->Option<Self::Item> {
+        if let Some(err) = self.error.take() {
+            return Some(Err(err));
+        }
+        let entry = match self.option.order {
+            WalkOrder::DepthFirst => self.pending.pop_back(),
+            WalkOrder::BreadthFirst => self.pending.pop_front(),
+        }?;
+        if entry.leaf.is_directory() && entry.depth < self.option.max_depth {
+            if let Err(err) = self.expand(&entry.path, entry.depth + 1) {
+                self.error = Some(err);
+            }
+        }
+        Some(Ok(entry))
+    }
+}
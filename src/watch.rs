@@ -0,0 +1,244 @@
+//! # 变化通知
+//! 类似 inotify，任务在路径或目录上登记监视，文件系统在修改时产生事件
+//! 每个监视有自己的事件队列，可以非阻塞读取或等待
+//! 监视目录时，目录本身及其中各项的事件都会送达
+
+use core::{ops::BitOr, sync::atomic::{AtomicUsize, Ordering}};
+use alloc::{collections::{BTreeMap, VecDeque}, prelude::v1::*};
+use tisu_sync::ContentMutex;
+use crate::{IoError, Wait};
+
+/// 每个队列最多积压的事件数，超过后丢弃新事件并产生一次 OVERFLOW
+const QUEUE_LIMIT : usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EventMask(usize);
+
+impl EventMask {
+    pub const CREATE : Self = Self(1);
+    pub const DELETE : Self = Self(1 << 1);
+    pub const MODIFY : Self = Self(1 << 2);
+    /// 改名的两端以相同的 cookie 成对出现
+    pub const MOVED_FROM : Self = Self(1 << 3);
+    pub const MOVED_TO : Self = Self(1 << 4);
+    pub const ATTRIBUTE : Self = Self(1 << 5);
+    /// 队列已满，之后的事件被丢弃，总会送达
+    pub const OVERFLOW : Self = Self(1 << 6);
+
+    pub const RENAME : Self = Self(Self::MOVED_FROM.0 | Self::MOVED_TO.0);
+    pub const ALL : Self = Self((1 << 6) - 1);
+
+    pub fn val(self)->usize {
+        self.0
+    }
+
+    pub fn contains(self, other : Self)->bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersects(self, other : Self)->bool {
+        self.0 & other.0 != 0
+    }
+}
+
+impl BitOr for EventMask {
+    type Output = Self;
+
+    fn bitor(self, rhs : Self)->Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// ## 监视对象
+/// 路径不以 '/' 开头或结尾，根目录为空；目录以起始块号表示
+#[derive(Debug, Clone, PartialEq)]
+pub enum WatchTarget {
+    Path(String),
+    Directory(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct WatchEvent {
+    pub wd : usize,
+    pub mask : EventMask,
+    /// 发生变化的项的路径
+    pub path : String,
+    /// 所在目录的起始块号
+    pub dir_idx : usize,
+    /// 只用于改名，同一次改名的两个事件相同
+    pub cookie : usize,
+}
+
+struct Watch {
+    owner : usize,
+    target : WatchTarget,
+    mask : EventMask,
+    queue : VecDeque<WatchEvent>,
+    overflow : bool,
+}
+
+impl Watch {
+    /// 事件的对象本身或其所在目录是被监视的对象
+    fn matches(&self, event : &WatchEvent)->bool {
+        if !self.mask.intersects(event.mask) {
+            return false;
+        }
+        match &self.target {
+            WatchTarget::Path(path) => event.path == *path || parent_of(&event.path[..]) == &path[..],
+            WatchTarget::Directory(idx) => event.dir_idx == *idx,
+        }
+    }
+
+    fn push(&mut self, mut event : WatchEvent) {
+        if self.overflow {
+            return;
+        }
+        if self.queue.len() + 1 >= QUEUE_LIMIT {
+            event.mask = EventMask::OVERFLOW;
+            self.overflow = true;
+        }
+        self.queue.push_back(event);
+    }
+}
+
+struct WatchInner {
+    next : usize,
+    cookie : usize,
+    watches : BTreeMap<usize, Watch>,
+}
+
+pub struct WatchManager {
+    inner : ContentMutex<WatchInner>,
+    /// 有事件送达或监视被移除时增加，等待者以此判断入睡前是否错过了变化
+    changed : AtomicUsize,
+    waiter : &'static dyn Wait,
+}
+
+impl WatchManager {
+    pub fn new(waiter : &'static dyn Wait)->Self {
+        Self {
+            inner : ContentMutex::new(WatchInner {
+                next : 1,
+                cookie : 1,
+                watches : BTreeMap::new(),
+            }),
+            changed : AtomicUsize::new(0),
+            waiter,
+        }
+    }
+
+    /// 返回监视描述符
+    pub fn add(&self, owner : usize, target : WatchTarget, mask : EventMask)->usize {
+        let mut inner = self.inner.lock();
+        let wd = inner.next;
+        inner.next += 1;
+        inner.watches.insert(wd, Watch {
+            owner,
+            target,
+            mask,
+            queue : VecDeque::new(),
+            overflow : false,
+        });
+        wd
+    }
+
+    pub fn remove(&self, wd : usize)->bool {
+        let rt = self.inner.lock().watches.remove(&wd).is_some();
+        self.wake();
+        rt
+    }
+
+    /// 任务退出时移除其所有监视
+    pub fn release_task(&self, owner : usize) {
+        let mut inner = self.inner.lock();
+        let wds : Vec<usize> = inner.watches.iter()
+            .filter(|(_, w)| w.owner == owner)
+            .map(|(wd, _)| *wd)
+            .collect();
+        for wd in wds {
+            inner.watches.remove(&wd);
+        }
+        drop(inner);
+        self.wake();
+    }
+
+    /// 卸载时移除所有监视
+    pub fn clear(&self) {
+        self.inner.lock().watches.clear();
+        self.wake();
+    }
+
+    pub fn is_empty(&self)->bool {
+        self.inner.lock().watches.len() == 0
+    }
+
+    /// 为改名的一对事件取得 cookie
+    pub fn cookie(&self)->usize {
+        let mut inner = self.inner.lock();
+        inner.cookie += 1;
+        inner.cookie
+    }
+
+    /// 没有监视时直接返回，不分配内存
+    pub fn emit(&self, mask : EventMask, path : &str, dir_idx : usize, cookie : usize) {
+        let mut inner = self.inner.lock();
+        if inner.watches.len() == 0 {
+            return;
+        }
+        let event = WatchEvent {
+            wd : 0,
+            mask,
+            path : path.to_string(),
+            dir_idx,
+            cookie,
+        };
+        let mut delivered = false;
+        for (wd, watch) in inner.watches.iter_mut() {
+            if watch.matches(&event) {
+                let mut event = event.clone();
+                event.wd = *wd;
+                watch.push(event);
+                delivered = true;
+            }
+        }
+        drop(inner);
+        if delivered {
+            self.wake();
+        }
+    }
+
+    /// 取出队列中所有事件，没有事件时返回空，描述符无效时返回 None
+    pub fn read(&self, wd : usize)->Option<Vec<WatchEvent>> {
+        let mut inner = self.inner.lock();
+        let watch = inner.watches.get_mut(&wd)?;
+        watch.overflow = false;
+        Some(watch.queue.drain(..).collect())
+    }
+
+    /// 睡眠到至少有一个事件，描述符无效或被移除时返回 NotFound，被打断时返回 Interrupted
+    pub fn wait(&self, wd : usize)->Result<Vec<WatchEvent>, IoError> {
+        loop {
+            let seen = self.changed.load(Ordering::SeqCst);
+            let events = self.read(wd).ok_or(IoError::NotFound)?;
+            if events.len() > 0 {
+                return Ok(events);
+            }
+            if !self.waiter.wait(&self.changed, seen) {
+                return Err(IoError::Interrupted);
+            }
+        }
+    }
+
+    fn wake(&self) {
+        self.changed.fetch_add(1, Ordering::SeqCst);
+        self.waiter.wake(&self.changed);
+    }
+}
+
+/// 不含结尾 '/' 的父目录路径，根目录下的项为空
+fn parent_of(path : &str)->&str {
+    match path.rfind('/') {
+        Some(idx) => &path[..idx],
+        None => "",
+    }
+}